@@ -0,0 +1,97 @@
+//! Blocking variants of the update service and device traits, and of the updater, for targets without an executor
+//! such as bootloaders and bare-metal applications.
+//!
+//! The blocking `FirmwareUpdater` is driven by the same `UpdateMachine` as the async updater, so that both follow
+//! the same protocol.
+use crate::{
+    protocol::{BootState, Capabilities, Codec, Command, FailureReport, Status},
+    traits::{FirmwareStatus, FirmwareVersion},
+};
+
+mod serial;
+mod updater;
+
+pub use {serial::*, updater::*};
+
+/// Trait for the firmware update service, performing blocking requests.
+///
+/// The blocking updater cannot time out requests, so the service is responsible for returning an error if the
+/// update service does not respond in time.
+pub trait UpdateService {
+    /// Error type
+    type Error: core::fmt::Debug;
+
+    /// Send the status to the server, and return the Command responded by the service
+    /// rx buffer.
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error>;
+}
+
+/// Represents a device that can be updated by a blocking `FirmwareUpdater`.
+pub trait FirmwareDevice {
+    /// The preferred block size to be passed in write.
+    const MTU: usize;
+
+    /// The maximum size of firmware that can be written to the device.
+    const CAPACITY: usize = usize::MAX;
+
+    /// The optional protocol features implemented by the device itself, such as the codecs accepted by
+    /// `write_compressed`. Advertised to the update service in addition to the features of the updater.
+    const CAPABILITIES: Capabilities = Capabilities::NONE;
+
+    /// The expected version type for this device.
+    type Version: FirmwareVersion;
+
+    /// The error type.
+    type Error;
+
+    /// Return the status of the currently running firmware.
+    fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error>;
+
+    /// Prepare for starting the firmware update process. The total size of the firmware is provided
+    /// if known, and never exceeds `CAPACITY`. For compressed firmware, this is the size of the compressed firmware.
+    fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error>;
+
+    /// Write a block of firmware at the expected offset.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Write a block of compressed firmware at the expected offset in the compressed firmware. Only called with
+    /// codecs contained in `CAPABILITIES`.
+    ///
+    /// The checksum passed to `update` after writing compressed firmware is the checksum of the decompressed
+    /// firmware, which is not verified by the updater. The device verifies it, and checks the size of the
+    /// decompressed firmware against its capacity.
+    fn write_compressed(&mut self, codec: Codec, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let _ = codec;
+        self.write(offset, data)
+    }
+
+    /// Finish the firmware write and mark device to be updated
+    fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error>;
+
+    /// Mark firmware as being in sync with the expected
+    fn synced(&mut self) -> Result<(), Self::Error>;
+
+    /// Discard the firmware being written, after the update service aborted the update.
+    fn abort(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the boot state of the running firmware. Devices with a bootloader that does not revert
+    /// firmware are always confirmed.
+    fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        Ok(BootState::Confirmed)
+    }
+
+    /// Mark the running firmware as good, so that the bootloader does not revert to the previous firmware.
+    fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the code reported to the update service when the update fails with the given error.
+    ///
+    /// Device specific codes should start at `FailureReport::DEVICE_SPECIFIC`.
+    fn error_code(error: &Self::Error) -> u32 {
+        let _ = error;
+        FailureReport::DEVICE_ERROR
+    }
+}
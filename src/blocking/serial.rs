@@ -0,0 +1,122 @@
+use {
+    super::UpdateService,
+    crate::{
+        protocol::{Command, Status},
+        service::{SerialError, FRAME_SIZE},
+    },
+    embedded_io::{Read, Write},
+    postcard::{from_bytes, to_slice},
+};
+
+/// A blocking update service based on the fixed-frame serial protocol of `service::Serial`, using `postcard` as the
+/// serialization format. Can be used with any transport implementing the blocking embedded-io traits.
+pub struct Serial<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    buf: [u8; FRAME_SIZE],
+}
+
+impl<T> Serial<T>
+where
+    T: Read + Write,
+{
+    /// Create an instance of a Serial update service over the provided transport.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            buf: [0; FRAME_SIZE],
+        }
+    }
+}
+
+impl<T> UpdateService for Serial<T>
+where
+    T: Read + Write,
+{
+    type Error = SerialError<T::Error, postcard::Error>;
+
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+        self.buf.fill(0);
+        to_slice(&status, &mut self.buf).map_err(SerialError::Codec)?;
+        self.transport.write_all(&self.buf).map_err(SerialError::Transport)?;
+
+        self.transport.read_exact(&mut self.buf)?;
+
+        let c: Command = from_bytes(&self.buf).map_err(SerialError::Codec)?;
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {super::*, core::convert::Infallible, embedded_io::ErrorType, std::vec::Vec};
+
+    /// A transport recording the frames written, and reading back the given bytes.
+    struct Transport {
+        written: Vec<u8>,
+        frame: Vec<u8>,
+    }
+
+    impl ErrorType for Transport {
+        type Error = Infallible;
+    }
+
+    impl Read for Transport {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let n = core::cmp::min(buf.len(), self.frame.len());
+            buf[..n].copy_from_slice(&self.frame[..n]);
+            self.frame.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Transport {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_blocking_serial() {
+        let mut frame = [0; FRAME_SIZE];
+        to_slice(&Command::new_sync(b"1", Some(10), Some(7)), &mut frame).unwrap();
+        let mut service = Serial::new(Transport {
+            written: Vec::new(),
+            frame: frame.to_vec(),
+        });
+
+        let status = Status::first(b"1", Some(256), Some(7));
+        let command = service.request(&status).unwrap();
+        assert_eq!(command.correlation_id(), Some(7));
+        assert!(matches!(command, Command::Sync { poll: Some(10), .. }));
+
+        assert_eq!(service.transport.written.len(), FRAME_SIZE);
+        let sent: Status = from_bytes(&service.transport.written).unwrap();
+        assert_eq!(sent.version, b"1");
+        assert_eq!(sent.correlation_id, Some(7));
+    }
+
+    #[test]
+    fn test_blocking_serial_truncated() {
+        // A command sent without padding it to a full frame
+        let mut frame = [0; FRAME_SIZE];
+        let len = to_slice(&Command::new_sync(b"1", Some(10), Some(7)), &mut frame)
+            .unwrap()
+            .len();
+        let mut service = Serial::new(Transport {
+            written: Vec::new(),
+            frame: frame[..len].to_vec(),
+        });
+
+        let status = Status::first(b"1", Some(256), Some(7));
+        assert!(matches!(service.request(&status), Err(SerialError::Truncated)));
+    }
+}
@@ -0,0 +1,409 @@
+use {
+    super::{FirmwareDevice, UpdateService},
+    crate::{
+        clock::{Clock, NoClock},
+        machine::{Action, DeviceProfile, UpdateMachine},
+        observer::{NoObserver, UpdateObserver},
+        policy::{AcceptAll, UpdatePolicy},
+        protocol::Capabilities,
+        retry::Backoff,
+        signature::{NoVerifier, SignatureVerifier},
+        updater::{DeviceStatus, Error, Failure, UpdaterConfig, UpdaterParts},
+    },
+    embedded_hal::delay::DelayNs,
+};
+
+/// The blocking variant of the updater process, following the update protocol with a blocking update service and
+/// device.
+///
+/// Requests are not timed out by the updater, and the `timeout_ms` of the configuration is not used. Patches and
+/// checkpoints of the update progress are only supported by the async updater: the `DELTA` capability is never
+/// advertised, and the progress of an interrupted update is not checkpointed.
+pub struct FirmwareUpdater<T, V = NoVerifier, O = NoObserver, C = NoClock, A = AcceptAll>
+where
+    T: UpdateService,
+    V: SignatureVerifier,
+    O: UpdateObserver,
+    C: Clock,
+    A: UpdatePolicy,
+{
+    service: T,
+    parts: UpdaterParts<V, O, C, A>,
+}
+
+impl<T> FirmwareUpdater<T>
+where
+    T: UpdateService,
+{
+    /// Create a new instance of the updater with the provided service instance.
+    pub fn new(service: T, config: UpdaterConfig) -> Self {
+        Self {
+            service,
+            parts: UpdaterParts::new(config),
+        }
+    }
+}
+
+impl<T, V, O, C, A> FirmwareUpdater<T, V, O, C, A>
+where
+    T: UpdateService,
+    V: SignatureVerifier,
+    O: UpdateObserver,
+    C: Clock,
+    A: UpdatePolicy,
+{
+    /// Use the provided verifier, like `crate::FirmwareUpdater::with_verifier`.
+    pub fn with_verifier<V2: SignatureVerifier>(self, verifier: V2) -> FirmwareUpdater<T, V2, O, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            parts: self.parts.with_verifier(verifier),
+        }
+    }
+
+    /// Use the provided observer, like `crate::FirmwareUpdater::with_observer`.
+    pub fn with_observer<O2: UpdateObserver>(self, observer: O2) -> FirmwareUpdater<T, V, O2, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            parts: self.parts.with_observer(observer),
+        }
+    }
+
+    /// Use the provided clock, like `crate::FirmwareUpdater::with_clock`.
+    pub fn with_clock<C2: Clock>(self, clock: C2) -> FirmwareUpdater<T, V, O, C2, A> {
+        FirmwareUpdater {
+            service: self.service,
+            parts: self.parts.with_clock(clock),
+        }
+    }
+
+    /// Use the provided policy, like `crate::FirmwareUpdater::with_policy`.
+    pub fn with_policy<A2: UpdatePolicy>(self, policy: A2) -> FirmwareUpdater<T, V, O, C, A2> {
+        FirmwareUpdater {
+            service: self.service,
+            parts: self.parts.with_policy(policy),
+        }
+    }
+
+    /// Seed the jitter of the retry policy, like `crate::FirmwareUpdater::with_seed`.
+    pub fn with_seed(self, seed: u32) -> Self {
+        Self {
+            service: self.service,
+            parts: self.parts.with_seed(seed),
+        }
+    }
+
+    /// Seed the correlation ids and the jitter of the retry policy, like `crate::FirmwareUpdater::with_rng`.
+    #[cfg(feature = "rand_core")]
+    pub fn with_rng<G: rand_core::RngCore>(self, rng: &mut G) -> Self {
+        Self {
+            service: self.service,
+            parts: self.parts.with_rng(rng),
+        }
+    }
+
+    /// Follow the commands of the update service until the device is in sync, updated or aborted, and report the
+    /// failure to the update service if the update fails.
+    fn check<F: FirmwareDevice, D: DelayNs>(
+        &mut self,
+        device: &mut F,
+        delay: &mut D,
+    ) -> Result<DeviceStatus, Error<F::Error, T::Error>> {
+        let status = device.status().map_err(Error::Device)?;
+        let boot = device.boot_state().map_err(Error::Device)?;
+        let profile = DeviceProfile {
+            mtu: F::MTU,
+            capacity: F::CAPACITY,
+            // Patches need a firmware reader, which only the async updater has
+            capabilities: F::CAPABILITIES.difference(Capabilities::DELTA),
+        };
+        let mut machine = UpdateMachine::new(self.parts.config.clone(), profile, status, boot)
+            .with_verifier(&mut self.parts.verifier)
+            .with_clock(&mut self.parts.clock)
+            .with_policy(&mut self.parts.policy);
+        machine.correlation_id = self.parts.correlation_id;
+        machine.backoff = core::mem::replace(&mut self.parts.backoff, Backoff::new(0));
+
+        if let Some(staged) = self.parts.staged.take() {
+            machine.restage(&staged.version, &staged.checksum);
+        }
+        let result = process(&mut self.service, device, delay, &mut machine, &mut self.parts.observer);
+        if let Err(e) = &result {
+            if let Some(status) = machine.report(e, F::error_code) {
+                debug!("Reporting failure: {:?}", status);
+                // The response is ignored, since the updater is giving up
+                let _ = self.service.request(&status);
+            }
+        }
+        self.parts.correlation_id = machine.correlation_id;
+        self.parts.staged = machine.take_staged();
+        self.parts.backoff = machine.backoff;
+        result
+    }
+
+    /// Run the firmware update protocol, blocking until the device is in sync, updated or the update is aborted.
+    /// See the async `FirmwareUpdater::run` for the outcomes of the update.
+    pub fn run<F: FirmwareDevice, D: DelayNs>(
+        &mut self,
+        device: &mut F,
+        delay: &mut D,
+    ) -> Result<DeviceStatus, Error<F::Error, T::Error>> {
+        self.check(device, delay).map_err(|e| {
+            self.parts.observer.failed();
+            e
+        })
+    }
+
+    /// Swap to the firmware staged by the last run of the updater with `deferred_swap` configured. See the async
+    /// `FirmwareUpdater::swap`.
+    pub fn swap<F: FirmwareDevice>(&mut self, device: &mut F) -> Result<bool, F::Error> {
+        let Some(staged) = &self.parts.staged else {
+            return Ok(false);
+        };
+        let status = device.status()?;
+        if status.next_version.as_ref().map(|v| v.as_ref()) != Some(&staged.version[..]) {
+            warn!("Staged firmware {:?} is no longer being written", &staged.version[..]);
+            self.parts.staged = None;
+            return Ok(false);
+        }
+        self.parts.observer.swapping(&staged.version);
+        device.update(&staged.version, &staged.checksum)?;
+        self.parts.staged = None;
+        Ok(true)
+    }
+}
+
+/// Send the status updates of the state machine to the update service, and perform the actions on the device, until
+/// the update is done.
+fn process<T, F, D, V, C, A, O>(
+    service: &mut T,
+    device: &mut F,
+    delay: &mut D,
+    machine: &mut UpdateMachine<F::Version, V, C, A>,
+    observer: &mut O,
+) -> Result<DeviceStatus, Error<F::Error, T::Error>>
+where
+    T: UpdateService,
+    F: FirmwareDevice,
+    D: DelayNs,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+    O: UpdateObserver,
+{
+    loop {
+        let request = machine.request();
+        let status = request.status();
+        debug!("Sending status: {:?}", status);
+
+        let response = service.request(&status).map_err(|e| {
+            debug!("Error reporting status: {:?}", debug2format!(e));
+            Failure::Service(e)
+        });
+
+        for action in machine.handle::<F::Error, T::Error>(response)? {
+            if let Action::Done(status) = action {
+                return Ok(status);
+            }
+            if let Err(e) = perform(device, delay, machine, observer, &action) {
+                machine.failed(&action);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Perform an action of the state machine on the device.
+fn perform<F, D, V, C, A, O, S>(
+    device: &mut F,
+    delay: &mut D,
+    machine: &UpdateMachine<F::Version, V, C, A>,
+    observer: &mut O,
+    action: &Action<'_>,
+) -> Result<(), Error<F::Error, S>>
+where
+    F: FirmwareDevice,
+    D: DelayNs,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+    O: UpdateObserver,
+{
+    match *action {
+        Action::Start { version, size } => {
+            device.start(version, size).map_err(Error::Device)?;
+            observer.started(version);
+        }
+        Action::Write { offset, data, codec } => {
+            match codec {
+                Some(codec) => device.write_compressed(codec, offset, data),
+                None => device.write(offset, data),
+            }
+            .map_err(Error::Device)?;
+            observer.written(offset, data.len() as u32, machine.total());
+        }
+        // Patches are never advertised, and rejected by the state machine
+        Action::Patch { .. } => return Err(Error::InvalidPatch),
+        Action::MarkBooted => device.mark_booted().map_err(Error::Device)?,
+        Action::Synced => {
+            device.synced().map_err(Error::Device)?;
+            observer.synced();
+        }
+        Action::Swap { version, checksum } => {
+            observer.swapping(version);
+            device.update(version, checksum).map_err(Error::Device)?;
+        }
+        Action::Stage { version, .. } => observer.staged(version),
+        Action::Abort { reason } => {
+            device.abort().map_err(Error::Device)?;
+            observer.aborted(reason);
+        }
+        Action::Wait(delay_ms) => {
+            observer.waiting(delay_ms);
+            delay.delay_ms(delay_ms);
+        }
+        Action::Retry { failures, delay_ms } => {
+            observer.retrying(failures, delay_ms);
+            delay.delay_ms(delay_ms);
+        }
+        Action::Checkpoint | Action::Done(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            device::Simulator,
+            protocol::{Command, Status},
+            service::InMemory,
+            RetryPolicy,
+        },
+    };
+
+    /// A delay recording the time it was asked to wait for, without waiting.
+    struct Recorded(u32);
+
+    impl DelayNs for Recorded {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0 += ns / 1_000_000;
+        }
+
+        fn delay_ms(&mut self, ms: u32) {
+            self.0 += ms;
+        }
+    }
+
+    /// An update service responding with the commands returned by a closure.
+    struct Scripted<F>(F);
+
+    impl<F> UpdateService for Scripted<F>
+    where
+        F: FnMut(&Status<'_>) -> Command<'static>,
+    {
+        type Error = ();
+
+        fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            Ok((self.0)(status))
+        }
+    }
+
+    /// An update service failing the given number of requests before responding.
+    struct Failing(usize);
+
+    impl UpdateService for Failing {
+        type Error = ();
+
+        fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            if self.0 == 0 {
+                return Ok(Command::new_sync(b"1", None, status.correlation_id));
+            }
+            self.0 -= 1;
+            Err(())
+        }
+    }
+
+    fn config() -> UpdaterConfig {
+        UpdaterConfig {
+            retry: RetryPolicy {
+                initial_delay_ms: 10,
+                multiplier: 2,
+                max_delay_ms: 40,
+                jitter_ms: 0,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_blocking_updated() {
+        let firmware = [1; 1024];
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &firmware), config());
+
+        let status = updater.run(&mut device, &mut Recorded(0)).unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.version(), b"2");
+
+        let status = updater.run(&mut device, &mut Recorded(0)).unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+    }
+
+    #[test]
+    fn test_blocking_deferred_swap() {
+        let firmware = [1; 1024];
+        let mut device = Simulator::new(b"1");
+        let config = UpdaterConfig {
+            deferred_swap: true,
+            ..config()
+        };
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &firmware), config);
+
+        let status = updater.run(&mut device, &mut Recorded(0)).unwrap();
+        assert_eq!(
+            status,
+            DeviceStatus::ReadyToSwap(heapless::Vec::from_slice(b"2").unwrap())
+        );
+        assert_eq!(device.version(), b"1");
+
+        assert!(updater.swap(&mut device).unwrap());
+        assert_eq!(device.version(), b"2");
+    }
+
+    #[test]
+    fn test_blocking_retry() {
+        let mut device = Simulator::new(b"1");
+        let mut delay = Recorded(0);
+        let mut updater = FirmwareUpdater::new(Failing(3), config());
+        let status = updater.run(&mut device, &mut delay).unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+        assert_eq!(delay.0, 10 + 20 + 40);
+
+        let mut updater = FirmwareUpdater::new(
+            Failing(3),
+            UpdaterConfig {
+                max_consecutive_failures: Some(2),
+                ..config()
+            },
+        );
+        let result = updater.run(&mut device, &mut Recorded(0));
+        assert!(matches!(result, Err(Error::RetriesExhausted(Failure::Service(())))));
+    }
+
+    #[test]
+    fn test_blocking_patch_unsupported() {
+        let mut advertised = std::vec::Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            advertised.push(status.supports(Capabilities::DELTA));
+            Command::new_patch(b"2", 0, &[1, 2, 3, 4], status.correlation_id)
+        });
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(service, config());
+        let result = updater.run(&mut device, &mut Recorded(0));
+        assert!(matches!(result, Err(Error::InvalidPatch)));
+        assert!(advertised.iter().all(|delta| !delta));
+        assert_eq!(device.version(), b"1");
+    }
+}
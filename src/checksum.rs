@@ -0,0 +1,230 @@
+#[cfg(feature = "sha256")]
+use sha2::{compress256, digest::generic_array::GenericArray, Digest, Sha256};
+
+/// The size in bytes of the checksum carried in `Command::Swap`.
+#[cfg(feature = "sha256")]
+pub const CHECKSUM_SIZE: usize = 32;
+
+/// The maximum size in bytes of a saved checksum state.
+#[cfg(feature = "sha256")]
+pub(crate) const STATE_SIZE: usize = 32 + 8 + 64;
+#[cfg(not(feature = "sha256"))]
+pub(crate) const STATE_SIZE: usize = 0;
+
+/// Running digest over the firmware blocks written to a device.
+///
+/// Without the `sha256` feature, no digest is computed and every checksum is accepted.
+#[derive(Clone)]
+pub(crate) struct Checksum {
+    #[cfg(feature = "sha256")]
+    hasher: Option<Hasher>,
+}
+
+impl Checksum {
+    /// Create a checksum that has not seen the start of an image.
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "sha256")]
+            hasher: None,
+        }
+    }
+
+    /// Start a new digest at offset 0 of an image.
+    pub(crate) fn reset(&mut self) {
+        #[cfg(feature = "sha256")]
+        self.hasher.replace(Hasher::new());
+    }
+
+    /// Returns true if the checksum is able to verify a transfer starting at the given offset.
+    ///
+    /// A transfer resumed at a non-zero offset can only be verified if the digest of the blocks
+    /// written before the resume has been restored.
+    pub(crate) fn can_resume(&self, offset: u32) -> bool {
+        #[cfg(feature = "sha256")]
+        return offset == 0 || self.hasher.as_ref().is_some_and(|h| h.len == offset as u64);
+        #[cfg(not(feature = "sha256"))]
+        {
+            let _ = offset;
+            true
+        }
+    }
+
+    /// Feed the next block of the image into the digest.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        #[cfg(feature = "sha256")]
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+        #[cfg(not(feature = "sha256"))]
+        let _ = data;
+    }
+
+    /// Compare the digest of all blocks written so far with the expected checksum.
+    pub(crate) fn verify(&self, expected: &[u8]) -> bool {
+        #[cfg(feature = "sha256")]
+        return match &self.hasher {
+            Some(hasher) => hasher.clone().finalize() == expected,
+            None => false,
+        };
+        #[cfg(not(feature = "sha256"))]
+        {
+            let _ = expected;
+            true
+        }
+    }
+
+    /// Save the state of the digest, so that it can be restored after a reset. Returns the number of bytes
+    /// written to `buf`, or `None` if no digest has been started.
+    #[cfg_attr(not(feature = "sha256"), allow(clippy::needless_pass_by_ref_mut))]
+    pub(crate) fn save(&self, buf: &mut [u8; STATE_SIZE]) -> Option<usize> {
+        #[cfg(feature = "sha256")]
+        return self.hasher.as_ref().map(|hasher| hasher.save(buf));
+        #[cfg(not(feature = "sha256"))]
+        {
+            let _ = buf;
+            Some(0)
+        }
+    }
+
+    /// Restore a digest state saved with `save`.
+    pub(crate) fn restore(data: &[u8]) -> Option<Self> {
+        #[cfg(feature = "sha256")]
+        return Some(Self {
+            hasher: Some(Hasher::restore(data)?),
+        });
+        #[cfg(not(feature = "sha256"))]
+        data.is_empty().then(Self::new)
+    }
+}
+
+/// A SHA-256 digest whose intermediate state can be saved and restored.
+#[cfg(feature = "sha256")]
+#[derive(Clone)]
+struct Hasher {
+    state: [u32; 8],
+    block: [u8; 64],
+    len: u64,
+}
+
+#[cfg(feature = "sha256")]
+impl Hasher {
+    const INITIAL: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    fn new() -> Self {
+        Self {
+            state: Self::INITIAL,
+            block: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let pos = (self.len % 64) as usize;
+            let n = core::cmp::min(64 - pos, data.len());
+            self.block[pos..pos + n].copy_from_slice(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+            if pos + n == 64 {
+                compress256(&mut self.state, &[GenericArray::clone_from_slice(&self.block)]);
+            }
+        }
+    }
+
+    fn finalize(mut self) -> [u8; CHECKSUM_SIZE] {
+        let bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.len % 64 != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0; CHECKSUM_SIZE];
+        for (out, word) in digest.chunks_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn save(&self, buf: &mut [u8; STATE_SIZE]) -> usize {
+        for (out, word) in buf[..32].chunks_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        buf[32..40].copy_from_slice(&self.len.to_be_bytes());
+        let pending = (self.len % 64) as usize;
+        buf[40..40 + pending].copy_from_slice(&self.block[..pending]);
+        40 + pending
+    }
+
+    fn restore(data: &[u8]) -> Option<Self> {
+        let mut hasher = Self::new();
+        let (state, rest) = (data.get(..32)?, data.get(32..)?);
+        for (word, bytes) in hasher.state.iter_mut().zip(state.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().ok()?);
+        }
+        hasher.len = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+        let pending = &rest[8..];
+        if pending.len() != (hasher.len % 64) as usize {
+            return None;
+        }
+        hasher.block[..pending.len()].copy_from_slice(pending);
+        Some(hasher)
+    }
+}
+
+/// Compute the checksum of a complete firmware image, as expected in `Command::Swap`.
+#[cfg(feature = "sha256")]
+pub fn checksum(firmware: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    Sha256::digest(firmware).into()
+}
+
+#[cfg(all(test, feature = "sha256"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest() {
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8);
+        // The lengths around the block boundaries where the padding spills into an extra block
+        for len in [0, 1, 55, 56, 63, 64, 65, 119, 120, 127, 128, 183, 184, 191, 192, 300] {
+            for split in [0, 1, 55, 56, 63, 64, 119, len / 3] {
+                let split = split.min(len);
+                let mut hasher = Hasher::new();
+                hasher.update(&data[..split]);
+                hasher.update(&data[split..len]);
+                assert_eq!(hasher.finalize(), checksum(&data[..len]));
+            }
+        }
+    }
+
+    #[test]
+    fn save_restore() {
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8);
+        for len in [55, 56, 63, 64, 119, 300] {
+            let expected = super::checksum(&data[..len]);
+            // Save at every offset within and at the end of each block
+            for split in 0..=len {
+                let mut checksum = Checksum::new();
+                checksum.reset();
+                checksum.update(&data[..split]);
+
+                let mut buf = [0; STATE_SIZE];
+                let saved = checksum.save(&mut buf).unwrap();
+                assert_eq!(saved, 40 + split % 64);
+                let mut restored = Checksum::restore(&buf[..saved]).unwrap();
+                assert!(restored.can_resume(split as u32));
+                assert!(!restored.can_resume(split as u32 + 1));
+                restored.update(&data[split..len]);
+                assert!(restored.verify(&expected), "len {} split {}", len, split);
+
+                // A state missing or carrying extra pending bytes is rejected
+                assert!(Checksum::restore(&buf[..saved - 1]).is_none());
+                assert!(Checksum::restore(&buf[..saved + 1]).is_none());
+            }
+        }
+
+        assert!(Checksum::restore(&[0; 10]).is_none());
+    }
+}
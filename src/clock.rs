@@ -0,0 +1,66 @@
+/// A monotonic clock, used by the updater to measure the time elapsed since the start of an update.
+pub trait Clock {
+    /// Whether the clock measures time. The elapsed time is estimated from the delays of the updater if false.
+    const ENABLED: bool = true;
+
+    /// Return the time in milliseconds since an arbitrary origin. The time must never decrease.
+    fn now_ms(&mut self) -> u64;
+}
+
+/// A clock for devices without a monotonic timer.
+///
+/// The time elapsed since the start of an update is estimated from the time the updater spends in delays and timed
+/// out requests, not including the time spent in requests answered by the update service or in writing firmware.
+pub struct NoClock;
+
+impl Clock for NoClock {
+    const ENABLED: bool = false;
+
+    fn now_ms(&mut self) -> u64 {
+        0
+    }
+}
+
+impl<C> Clock for &mut C
+where
+    C: Clock,
+{
+    const ENABLED: bool = C::ENABLED;
+
+    fn now_ms(&mut self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+#[cfg(feature = "std")]
+pub use stdlib::StdClock;
+
+#[cfg(feature = "std")]
+mod stdlib {
+    extern crate std;
+    use std::time::Instant;
+
+    /// A clock measuring time with `std::time::Instant`.
+    pub struct StdClock {
+        origin: Instant,
+    }
+
+    impl StdClock {
+        /// Create a clock with the current instant as origin.
+        pub fn new() -> Self {
+            Self { origin: Instant::now() }
+        }
+    }
+
+    impl Default for StdClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl super::Clock for StdClock {
+        fn now_ms(&mut self) -> u64 {
+            self.origin.elapsed().as_millis() as u64
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use {
+    crate::{checksum::Checksum, traits::FirmwareDevice, updater::Error},
+    core::convert::Infallible,
+};
+
+/// Reads back the firmware currently running on a device, which patches sent by the update service are
+/// applied against.
+#[allow(async_fn_in_trait)]
+pub trait FirmwareReader {
+    /// Whether the current firmware can be read back. Patches are only requested from the update service if true.
+    const ENABLED: bool = true;
+
+    /// Error type
+    type Error: core::fmt::Debug;
+
+    /// Fill `buf` with the current firmware starting at `offset`.
+    async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// A reader for devices that cannot read back their current firmware, so that patches are never requested.
+pub struct NoReader;
+
+impl FirmwareReader for NoReader {
+    const ENABLED: bool = false;
+    type Error = Infallible;
+
+    async fn read(&mut self, _offset: u32, _buf: &mut [u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A reader for a firmware image in memory, such as the memory mapped flash of the running firmware.
+impl FirmwareReader for &[u8] {
+    type Error = ();
+
+    async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        let image = start
+            .checked_add(buf.len())
+            .and_then(|end| self.get(start..end))
+            .ok_or(())?;
+        buf.copy_from_slice(image);
+        Ok(())
+    }
+}
+
+impl<R> FirmwareReader for &mut R
+where
+    R: FirmwareReader,
+{
+    const ENABLED: bool = R::ENABLED;
+    type Error = R::Error;
+
+    async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        (**self).read(offset, buf).await
+    }
+}
+
+/// Size of the control record preceding the diff and extra data of every entry of a patch.
+const CONTROL_SIZE: usize = 12;
+
+/// Size of the buffer used for reading back the current firmware.
+const CHUNK_SIZE: usize = 64;
+
+/// A patch being applied against the current firmware.
+///
+/// Patches use the layout of `bsdiff`, as a sequence of entries that each start with a control record of
+/// three little endian integers: the `u32` length of the diff data, the `u32` length of the extra data, and
+/// the `i32` distance to seek in the current firmware after the entry. The diff data is added bytewise to
+/// the current firmware to produce the new firmware, and the extra data is copied as is.
+///
+/// The patch is applied as it is received, so that only a control record and a chunk of the current firmware
+/// are buffered at any time.
+#[derive(Clone)]
+pub(crate) struct Patch {
+    /// The offset of the next block of the patch.
+    offset: u32,
+    /// The control record being received.
+    control: [u8; CONTROL_SIZE],
+    /// The number of bytes of the control record received.
+    filled: usize,
+    /// The remaining diff data of the current entry.
+    diff: u32,
+    /// The remaining extra data of the current entry.
+    extra: u32,
+    /// The distance to seek in the current firmware after the current entry.
+    seek: i32,
+    /// The position in the current firmware.
+    old: u32,
+}
+
+impl Patch {
+    /// Create a patch that has not seen any data.
+    pub(crate) fn new() -> Self {
+        Self {
+            offset: 0,
+            control: [0; CONTROL_SIZE],
+            filled: 0,
+            diff: 0,
+            extra: 0,
+            seek: 0,
+            old: 0,
+        }
+    }
+
+    /// The offset of the next block of the patch.
+    pub(crate) fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Apply the next block of the patch, writing the produced firmware to the device at `next_offset`.
+    pub(crate) async fn apply<F: FirmwareDevice, R: FirmwareReader, S>(
+        &mut self,
+        device: &mut F,
+        reader: &mut R,
+        checksum: &mut Checksum,
+        next_offset: &mut u32,
+        mut data: &[u8],
+    ) -> Result<(), Error<F::Error, S>> {
+        while !data.is_empty() {
+            let n = if self.diff == 0 && self.extra == 0 {
+                let n = core::cmp::min(CONTROL_SIZE - self.filled, data.len());
+                self.control[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+                self.filled += n;
+                if self.filled == CONTROL_SIZE {
+                    let [diff, extra, seek] = core::array::from_fn(|i| {
+                        let mut word = [0; 4];
+                        word.copy_from_slice(&self.control[i * 4..i * 4 + 4]);
+                        word
+                    });
+                    self.old = self.old.wrapping_add(self.seek as u32);
+                    self.diff = u32::from_le_bytes(diff);
+                    self.extra = u32::from_le_bytes(extra);
+                    self.seek = i32::from_le_bytes(seek);
+                    self.filled = 0;
+                }
+                n
+            } else if self.diff > 0 {
+                let n = core::cmp::min(core::cmp::min(self.diff as usize, data.len()), CHUNK_SIZE);
+                let mut chunk = [0; CHUNK_SIZE];
+                let chunk = &mut chunk[..n];
+                if let Err(e) = reader.read(self.old, chunk).await {
+                    warn!("Error reading back firmware: {:?}", debug2format!(e));
+                    return Err(Error::InvalidPatch);
+                }
+                for (b, d) in chunk.iter_mut().zip(data) {
+                    *b = b.wrapping_add(*d);
+                }
+                write(device, checksum, next_offset, chunk).await?;
+                self.old = self.old.wrapping_add(n as u32);
+                self.diff -= n as u32;
+                n
+            } else {
+                let n = core::cmp::min(self.extra as usize, data.len());
+                write(device, checksum, next_offset, &data[..n]).await?;
+                self.extra -= n as u32;
+                n
+            };
+            self.offset += n as u32;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+}
+
+/// Write a block of the produced firmware to the device.
+async fn write<F: FirmwareDevice, S>(
+    device: &mut F,
+    checksum: &mut Checksum,
+    next_offset: &mut u32,
+    data: &[u8],
+) -> Result<(), Error<F::Error, S>> {
+    let end = next_offset.saturating_add(data.len() as u32);
+    if end as usize > F::CAPACITY {
+        warn!("Firmware does not fit in device");
+        return Err(Error::ImageTooLarge(end));
+    }
+    device.write(*next_offset, data).await.map_err(Error::Device)?;
+    checksum.update(data);
+    *next_offset = end;
+    Ok(())
+}
@@ -0,0 +1,426 @@
+use {
+    crate::{
+        checksum::CHECKSUM_SIZE,
+        manifest::{ComponentDevice, Manifest, MANIFEST_SIZE, MAX_COMPONENTS},
+        protocol::{BootState, FailureReport},
+        traits::{FirmwareDevice, FirmwareStatus, FirmwareVersion},
+    },
+    heapless::Vec,
+    sha2::{Digest, Sha256},
+};
+
+/// Size of the length preceding the manifest of a multi-component firmware.
+const LENGTH_SIZE: usize = 4;
+
+/// The error type of a `Components` device.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ComponentError<E> {
+    /// Error from the underlying device.
+    Device(E),
+    /// The manifest could not be decoded, is not for the version being written, or does not match the images.
+    InvalidManifest,
+    /// The digest of the image of a component does not match the manifest. Contains the index of the component.
+    DigestMismatch(u8),
+}
+
+/// A device adapter updating the components of a `ComponentDevice` from a multi-component firmware described by
+/// a `Manifest`.
+///
+/// The adapter walks the manifest at the start of the firmware, and starts every component whose version differs
+/// from the one listed. The images are then passed to their components as they are received, skipping the images
+/// of components that are already up to date, and the digest of every image is checked against the manifest. The
+/// components are only swapped once every image has been verified.
+///
+/// The state of the adapter is not persisted, and an update interrupted by a reset is restarted from the start.
+pub struct Components<D>
+where
+    D: ComponentDevice,
+{
+    device: D,
+    next_version: Option<D::Version>,
+    offset: u32,
+    manifest: Vec<u8, { LENGTH_SIZE + MANIFEST_SIZE }>,
+    entries: Option<Vec<Entry, MAX_COMPONENTS>>,
+    current: usize,
+    written: u32,
+    hasher: Sha256,
+}
+
+/// A component listed in the manifest of the firmware being written.
+struct Entry {
+    index: u8,
+    size: u32,
+    digest: [u8; CHECKSUM_SIZE],
+    install: bool,
+}
+
+impl<D> Components<D>
+where
+    D: ComponentDevice,
+{
+    /// Create an adapter updating the components of `device`.
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            next_version: None,
+            offset: 0,
+            manifest: Vec::new(),
+            entries: None,
+            current: 0,
+            written: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Return the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Forget the progress of the firmware being written.
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.manifest.clear();
+        self.entries = None;
+        self.current = 0;
+        self.written = 0;
+        self.hasher = Sha256::new();
+    }
+
+    /// The size of the length and manifest, once the length has been received. Returns `None` if the manifest does
+    /// not fit in `MANIFEST_SIZE`.
+    fn manifest_size(&self) -> Option<usize> {
+        match self.manifest.get(..LENGTH_SIZE) {
+            Some(length) => {
+                let length = u32::from_le_bytes(length.try_into().ok()?);
+                if length > MANIFEST_SIZE as u32 {
+                    warn!("Manifest too large: {}", length);
+                    return None;
+                }
+                LENGTH_SIZE.checked_add(length as usize)
+            }
+            None => Some(LENGTH_SIZE),
+        }
+    }
+
+    /// Receive the next bytes of the manifest, returning the number of bytes consumed.
+    fn receive(&mut self, data: &[u8]) -> Result<usize, ComponentError<D::Error>> {
+        let expected = self.manifest_size().ok_or(ComponentError::InvalidManifest)?;
+        let n = core::cmp::min(expected.saturating_sub(self.manifest.len()), data.len());
+        self.manifest
+            .extend_from_slice(&data[..n])
+            .map_err(|_| ComponentError::InvalidManifest)?;
+        Ok(n)
+    }
+
+    /// Walk the received manifest, starting every component that is not up to date.
+    async fn walk(&mut self) -> Result<Vec<Entry, MAX_COMPONENTS>, ComponentError<D::Error>> {
+        let manifest = Manifest::decode(&self.manifest[LENGTH_SIZE..]).ok_or(ComponentError::InvalidManifest)?;
+        if self.next_version.as_ref().map(|v| v.as_ref()) != Some(manifest.version.as_ref()) {
+            warn!("Manifest is not for the version being written");
+            return Err(ComponentError::InvalidManifest);
+        }
+
+        let mut entries = Vec::new();
+        for component in manifest.components.iter() {
+            let digest = component
+                .digest
+                .as_ref()
+                .try_into()
+                .map_err(|_| ComponentError::InvalidManifest)?;
+            let current = self
+                .device
+                .component_version(component.index)
+                .await
+                .map_err(ComponentError::Device)?;
+            let install = current.as_ref() != component.version.as_ref();
+            if install {
+                debug!("Updating component {}", component.index);
+                self.device
+                    .start(component.index, &component.version, component.size)
+                    .await
+                    .map_err(ComponentError::Device)?;
+            }
+            let entry = Entry {
+                index: component.index,
+                size: component.size,
+                digest,
+                install,
+            };
+            entries.push(entry).map_err(|_| ComponentError::InvalidManifest)?;
+        }
+        Ok(entries)
+    }
+}
+
+impl<D> FirmwareDevice for Components<D>
+where
+    D: ComponentDevice,
+{
+    const MTU: usize = D::MTU;
+    type Version = D::Version;
+    type Error = ComponentError<D::Error>;
+
+    async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        let current_version = self.device.version().await.map_err(ComponentError::Device)?;
+        Ok(FirmwareStatus {
+            current_version,
+            next_offset: self.offset,
+            next_version: self.next_version.clone(),
+        })
+    }
+
+    async fn start(&mut self, version: &[u8], _size: Option<u32>) -> Result<(), Self::Error> {
+        self.reset();
+        self.next_version = D::Version::from_slice(version).ok();
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, mut data: &[u8]) -> Result<(), Self::Error> {
+        if offset == 0 {
+            self.reset();
+        }
+        self.offset = offset.saturating_add(data.len() as u32);
+        while !data.is_empty() {
+            let Some(entries) = &self.entries else {
+                let n = self.receive(data)?;
+                data = &data[n..];
+                if Some(self.manifest.len()) == self.manifest_size() {
+                    self.entries = Some(self.walk().await?);
+                }
+                continue;
+            };
+
+            let entry = entries.get(self.current).ok_or(ComponentError::InvalidManifest)?;
+            let n = core::cmp::min(entry.size.saturating_sub(self.written) as usize, data.len());
+            if entry.install {
+                self.device
+                    .write(entry.index, self.written, &data[..n])
+                    .await
+                    .map_err(ComponentError::Device)?;
+            }
+            self.hasher.update(&data[..n]);
+            self.written += n as u32;
+            data = &data[n..];
+
+            if self.written == entry.size {
+                if self.hasher.finalize_reset()[..] != entry.digest {
+                    warn!("Digest mismatch for component {}", entry.index);
+                    return Err(ComponentError::DigestMismatch(entry.index));
+                }
+                self.current += 1;
+                self.written = 0;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update(&mut self, version: &[u8], _checksum: &[u8]) -> Result<(), Self::Error> {
+        match &self.entries {
+            Some(entries) if self.current == entries.len() => {}
+            _ => {
+                warn!("Firmware ended before all components were written");
+                return Err(ComponentError::InvalidManifest);
+            }
+        }
+        self.device.update(version).await.map_err(ComponentError::Device)?;
+        self.reset();
+        self.next_version = None;
+        Ok(())
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        self.device.synced().await.map_err(ComponentError::Device)
+    }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        self.reset();
+        self.next_version = None;
+        self.device.abort().await.map_err(ComponentError::Device)
+    }
+
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        self.device.boot_state().await.map_err(ComponentError::Device)
+    }
+
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        self.device.mark_booted().await.map_err(ComponentError::Device)
+    }
+
+    fn error_code(error: &Self::Error) -> u32 {
+        match error {
+            ComponentError::Device(e) => D::error_code(e),
+            ComponentError::InvalidManifest => FailureReport::INVALID_MANIFEST,
+            ComponentError::DigestMismatch(_) => FailureReport::CHECKSUM_MISMATCH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            device::testing::TokioDelay, manifest::Component, service::InMemory, DeviceStatus, FirmwareUpdater,
+            UpdaterConfig,
+        },
+        core::convert::Infallible,
+        std::vec::Vec,
+    };
+
+    type Version = heapless::Vec<u8, 16>;
+
+    /// A simulated device with three components, keeping the images written to every component until they are
+    /// swapped.
+    struct Cores {
+        version: Version,
+        running: [(Version, Vec<u8>); 3],
+        staged: [Option<(Version, Vec<u8>)>; 3],
+    }
+
+    impl Cores {
+        fn new() -> Self {
+            let image = |version: &[u8]| (Version::from_slice(version).unwrap(), Vec::new());
+            Self {
+                version: Version::from_slice(b"1").unwrap(),
+                running: [image(b"a1"), image(b"b1"), image(b"c1")],
+                staged: [None, None, None],
+            }
+        }
+    }
+
+    impl ComponentDevice for Cores {
+        const MTU: usize = 256;
+        type Version = Version;
+        type Error = Infallible;
+
+        async fn version(&mut self) -> Result<Self::Version, Self::Error> {
+            Ok(self.version.clone())
+        }
+
+        async fn component_version(&mut self, component: u8) -> Result<Self::Version, Self::Error> {
+            Ok(self.running[component as usize].0.clone())
+        }
+
+        async fn start(&mut self, component: u8, version: &[u8], _size: u32) -> Result<(), Self::Error> {
+            self.staged[component as usize] = Some((Version::from_slice(version).unwrap(), Vec::new()));
+            Ok(())
+        }
+
+        async fn write(&mut self, component: u8, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+            let (_, image) = self.staged[component as usize].as_mut().unwrap();
+            assert_eq!(offset as usize, image.len());
+            image.extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn update(&mut self, version: &[u8]) -> Result<(), Self::Error> {
+            for (running, staged) in self.running.iter_mut().zip(self.staged.iter_mut()) {
+                if let Some(staged) = staged.take() {
+                    *running = staged;
+                }
+            }
+            self.version = Version::from_slice(version).unwrap();
+            Ok(())
+        }
+
+        async fn synced(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// The index, version, digest and contents of the image of a component.
+    type Image<'a> = (u8, &'a [u8], [u8; CHECKSUM_SIZE], &'a [u8]);
+
+    /// Build a multi-component firmware from the images of the components.
+    fn firmware(version: &[u8], images: &[Image]) -> Vec<u8> {
+        let mut manifest = Manifest::new(version);
+        for (index, version, digest, image) in images {
+            let component = Component::new(*index, version, image.len() as u32, digest);
+            manifest.components.push(component).unwrap();
+        }
+        let mut buf = [0; MANIFEST_SIZE];
+        let encoded = manifest.encode(&mut buf).unwrap();
+
+        let mut firmware = (encoded.len() as u32).to_le_bytes().to_vec();
+        firmware.extend_from_slice(encoded);
+        for (_, _, _, image) in images {
+            firmware.extend_from_slice(image);
+        }
+        firmware
+    }
+
+    #[tokio::test]
+    async fn test_update_components() {
+        let app: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let net = [2; 100];
+        let modem: Vec<u8> = (0..500).map(|i| (i / 3) as u8).collect();
+        let firmware = firmware(
+            b"2",
+            &[
+                (0, b"a2", crate::checksum(&app), &app),
+                (1, b"b1", crate::checksum(&net), &net),
+                (2, b"c2", crate::checksum(&modem), &modem),
+            ],
+        );
+
+        let service = InMemory::new(b"2", &firmware);
+        let mut device = Components::new(Cores::new());
+        let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+
+        let cores = device.into_inner();
+        assert_eq!(cores.version, b"2");
+        assert_eq!(cores.running[0], (Version::from_slice(b"a2").unwrap(), app));
+        // The network core is up to date, and its image is not written
+        assert_eq!(cores.running[1], (Version::from_slice(b"b1").unwrap(), Vec::new()));
+        assert_eq!(cores.running[2], (Version::from_slice(b"c2").unwrap(), modem));
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch() {
+        let app = [1; 300];
+        let modem = [3; 300];
+        let firmware = firmware(
+            b"2",
+            &[
+                (0, b"a2", crate::checksum(&app), &app),
+                (2, b"c2", crate::checksum(&app), &modem),
+            ],
+        );
+
+        let service = InMemory::new(b"2", &firmware);
+        let mut device = Components::new(Cores::new());
+        let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default());
+        let result = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::Device(ComponentError::DigestMismatch(2)))
+        ));
+
+        // No component is swapped
+        let cores = device.into_inner();
+        assert_eq!(cores.version, b"1");
+        assert_eq!(cores.running[0].0, b"a1");
+        assert_eq!(cores.running[2].0, b"c1");
+    }
+
+    #[tokio::test]
+    async fn test_manifest_too_large() {
+        let mut firmware = u32::MAX.to_le_bytes().to_vec();
+        firmware.extend_from_slice(&[0; 300]);
+
+        let service = InMemory::new(b"2", &firmware);
+        let mut device = Components::new(Cores::new());
+        let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default());
+        let result = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::Device(ComponentError::InvalidManifest))
+        ));
+        assert_eq!(device.into_inner().version, b"1");
+    }
+}
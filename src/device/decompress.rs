@@ -0,0 +1,419 @@
+use crate::{
+    checksum::Checksum,
+    protocol::{BootState, Capabilities, Codec, FailureReport},
+    traits::{FirmwareDevice, FirmwareStatus},
+};
+
+/// Size of the buffer used for writing decompressed firmware to the device.
+const CHUNK_SIZE: usize = 128;
+
+/// The error type of a `Decompress` device.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecompressError<E> {
+    /// Error from the underlying device.
+    Device(E),
+    /// The decompressed firmware does not fit in the underlying device.
+    ImageTooLarge(u32),
+    /// The checksum of the decompressed firmware does not match the checksum of the update.
+    ChecksumMismatch,
+}
+
+/// A device adapter decompressing compressed firmware blocks on the fly into the underlying device.
+///
+/// The adapter accepts `Codec::Heatshrink`, and reports offsets in the compressed firmware in its status, so that
+/// the update service can continue sending compressed blocks after a lost response. The size of the decompressed
+/// firmware is not known in advance, so the underlying device is started without a size, and the adapter checks
+/// the decompressed firmware against the capacity of the underlying device and the checksum of the update. The
+/// state of the decoder is not persisted, and an update interrupted by a reset is restarted from the start.
+pub struct Decompress<D>
+where
+    D: FirmwareDevice,
+{
+    device: D,
+    decoder: Heatshrink,
+    /// The digest of the decompressed firmware, if the firmware is compressed.
+    checksum: Option<Checksum>,
+    offset: u32,
+    written: u32,
+}
+
+impl<D> Decompress<D>
+where
+    D: FirmwareDevice,
+{
+    /// Create an adapter decompressing firmware into `device`.
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            decoder: Heatshrink::new(),
+            checksum: None,
+            offset: 0,
+            written: 0,
+        }
+    }
+
+    /// Return the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Prepare for a firmware written from offset 0.
+    fn reset(&mut self) {
+        self.decoder = Heatshrink::new();
+        self.checksum = None;
+        self.offset = 0;
+        self.written = 0;
+    }
+}
+
+impl<D> FirmwareDevice for Decompress<D>
+where
+    D: FirmwareDevice,
+{
+    const MTU: usize = D::MTU;
+    const CAPACITY: usize = D::CAPACITY;
+    const CAPABILITIES: Capabilities = D::CAPABILITIES.union(Capabilities::HEATSHRINK);
+    type Version = D::Version;
+    type Error = DecompressError<D::Error>;
+
+    async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        let status = self.device.status().await.map_err(DecompressError::Device)?;
+        Ok(FirmwareStatus {
+            current_version: status.current_version,
+            next_offset: self.offset,
+            next_version: status.next_version,
+        })
+    }
+
+    async fn start(&mut self, version: &[u8], _size: Option<u32>) -> Result<(), Self::Error> {
+        self.reset();
+        self.device.start(version, None).await.map_err(DecompressError::Device)
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        if offset == 0 {
+            self.reset();
+        }
+        self.device
+            .write(self.written, data)
+            .await
+            .map_err(DecompressError::Device)?;
+        self.offset = offset + data.len() as u32;
+        self.written += data.len() as u32;
+        Ok(())
+    }
+
+    async fn write_compressed(&mut self, codec: Codec, offset: u32, mut data: &[u8]) -> Result<(), Self::Error> {
+        let Codec::Heatshrink = codec;
+        if offset == 0 {
+            self.reset();
+            let mut checksum = Checksum::new();
+            checksum.reset();
+            self.checksum = Some(checksum);
+        }
+        self.offset = offset + data.len() as u32;
+        let mut buf = [0; CHUNK_SIZE];
+        loop {
+            let n = self.decoder.decode(&mut data, &mut buf);
+            if n == 0 {
+                return Ok(());
+            }
+            let end = self.written.saturating_add(n as u32);
+            if end as usize > D::CAPACITY {
+                warn!("Decompressed firmware does not fit in device");
+                return Err(DecompressError::ImageTooLarge(end));
+            }
+            self.device
+                .write(self.written, &buf[..n])
+                .await
+                .map_err(DecompressError::Device)?;
+            if let Some(checksum) = &mut self.checksum {
+                checksum.update(&buf[..n]);
+            }
+            self.written = end;
+        }
+    }
+
+    async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
+        if self.checksum.as_ref().is_some_and(|c| !c.verify(checksum)) {
+            warn!("Checksum of the decompressed firmware does not match");
+            return Err(DecompressError::ChecksumMismatch);
+        }
+        self.reset();
+        self.device
+            .update(version, checksum)
+            .await
+            .map_err(DecompressError::Device)
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        self.device.synced().await.map_err(DecompressError::Device)
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Self::Error> {
+        self.device.checkpoint().await.map_err(DecompressError::Device)
+    }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        self.reset();
+        self.device.abort().await.map_err(DecompressError::Device)
+    }
+
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        self.device.boot_state().await.map_err(DecompressError::Device)
+    }
+
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        self.device.mark_booted().await.map_err(DecompressError::Device)
+    }
+
+    fn error_code(error: &Self::Error) -> u32 {
+        match error {
+            DecompressError::Device(e) => D::error_code(e),
+            DecompressError::ImageTooLarge(_) => FailureReport::IMAGE_TOO_LARGE,
+            DecompressError::ChecksumMismatch => FailureReport::CHECKSUM_MISMATCH,
+        }
+    }
+}
+
+/// Number of bits of the distance of a back-reference, the size of the window being `1 << WINDOW_BITS`.
+const WINDOW_BITS: u8 = 8;
+
+/// Number of bits of the length of a back-reference.
+const LOOKAHEAD_BITS: u8 = 4;
+
+/// The next item expected in the compressed stream.
+#[derive(Clone, Copy)]
+enum State {
+    Tag,
+    Literal,
+    Index,
+    Count(u16),
+    Copy(u16, u16),
+}
+
+/// A streaming heatshrink decoder.
+///
+/// The compressed stream is a sequence of bits, most significant bit first. A `1` bit is followed by a literal
+/// byte, and a `0` bit by a back-reference of `WINDOW_BITS` bits for the distance minus one, and `LOOKAHEAD_BITS`
+/// bits for the length minus one, into the window of the last decompressed bytes.
+struct Heatshrink {
+    window: [u8; 1 << WINDOW_BITS],
+    head: usize,
+    bits: u32,
+    count: u8,
+    state: State,
+}
+
+impl Heatshrink {
+    fn new() -> Self {
+        Self {
+            window: [0; 1 << WINDOW_BITS],
+            head: 0,
+            bits: 0,
+            count: 0,
+            state: State::Tag,
+        }
+    }
+
+    /// Read `n` bits from the input, or return `None` if the input is exhausted.
+    fn take(&mut self, input: &mut &[u8], n: u8) -> Option<u16> {
+        while self.count < n {
+            let (byte, rest) = input.split_first()?;
+            self.bits = (self.bits << 8) | *byte as u32;
+            self.count += 8;
+            *input = rest;
+        }
+        self.count -= n;
+        let value = (self.bits >> self.count) & ((1 << n) - 1);
+        self.bits &= (1 << self.count) - 1;
+        Some(value as u16)
+    }
+
+    /// Decompress the input into `out`, until the input is exhausted or `out` is full. Returns the number of
+    /// decompressed bytes.
+    fn decode(&mut self, input: &mut &[u8], out: &mut [u8]) -> usize {
+        let mask = self.window.len() - 1;
+        let mut n = 0;
+        while n < out.len() {
+            self.state = match self.state {
+                State::Tag => match self.take(input, 1) {
+                    Some(1) => State::Literal,
+                    Some(_) => State::Index,
+                    None => break,
+                },
+                State::Literal => {
+                    let Some(byte) = self.take(input, 8) else {
+                        break;
+                    };
+                    out[n] = byte as u8;
+                    self.window[self.head & mask] = byte as u8;
+                    self.head += 1;
+                    n += 1;
+                    State::Tag
+                }
+                State::Index => match self.take(input, WINDOW_BITS) {
+                    Some(index) => State::Count(index + 1),
+                    None => break,
+                },
+                State::Count(index) => match self.take(input, LOOKAHEAD_BITS) {
+                    Some(count) => State::Copy(index, count + 1),
+                    None => break,
+                },
+                State::Copy(index, count) => {
+                    let byte = self.window[self.head.wrapping_sub(index as usize) & mask];
+                    out[n] = byte;
+                    self.window[self.head & mask] = byte;
+                    self.head += 1;
+                    n += 1;
+                    if count > 1 {
+                        State::Copy(index, count - 1)
+                    } else {
+                        State::Tag
+                    }
+                }
+            };
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            device::testing::{Image, Probe, TokioDelay},
+            service::InMemory,
+            DeviceStatus, Error, FirmwareUpdater, UpdateObserver, UpdaterConfig,
+        },
+        std::vec::Vec,
+    };
+
+    /// Compress `data` with greedy matching of the longest back-reference.
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let (mut bits, mut count) = (0u32, 0);
+        let mut push = |value: u32, n: u32| {
+            bits = (bits << n) | value;
+            count += n;
+            while count >= 8 {
+                count -= 8;
+                out.push((bits >> count) as u8);
+            }
+        };
+        let mut pos = 0;
+        while pos < data.len() {
+            let (mut index, mut len) = (0, 0);
+            for distance in 1..=core::cmp::min(pos, 1 << WINDOW_BITS) {
+                let matching = data[pos..]
+                    .iter()
+                    .zip(&data[pos - distance..])
+                    .take(1 << LOOKAHEAD_BITS)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if matching > len {
+                    (index, len) = (distance, matching);
+                }
+            }
+            if len >= 2 {
+                push(0, 1);
+                push(index as u32 - 1, WINDOW_BITS as u32);
+                push(len as u32 - 1, LOOKAHEAD_BITS as u32);
+                pos += len;
+            } else {
+                push(1, 1);
+                push(data[pos] as u32, 8);
+                pos += 1;
+            }
+        }
+        push(0, 7);
+        out
+    }
+
+    #[test]
+    fn decode() {
+        let data: Vec<u8> = (0..2000).map(|i| (i % 7 * i % 13) as u8).collect();
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len());
+
+        for block in [1, 7, 64, compressed.len()] {
+            let mut decoder = Heatshrink::new();
+            let mut decoded = Vec::new();
+            for mut input in compressed.chunks(block) {
+                let mut buf = [0; 10];
+                loop {
+                    let n = decoder.decode(&mut input, &mut buf);
+                    if n == 0 {
+                        break;
+                    }
+                    decoded.extend_from_slice(&buf[..n]);
+                }
+            }
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_compressed() {
+        let firmware: Vec<u8> = (0..2000).map(|i| (i / 10) as u8).collect();
+        let compressed = compress(&firmware);
+
+        /// An observer recording the progress of the update.
+        struct Progress(Vec<(u32, u32, Option<u32>)>);
+
+        impl UpdateObserver for Progress {
+            fn written(&mut self, offset: u32, len: u32, total: Option<u32>) {
+                self.0.push((offset, len, total));
+            }
+        }
+
+        let service = InMemory::new(b"2", &firmware).with_compressed(Codec::Heatshrink, &compressed);
+        let mut device = Decompress::new(Image::new(b"1"));
+        let mut progress = Progress(Vec::new());
+        let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default()).with_observer(&mut progress);
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+
+        let image = device.into_inner();
+        assert_eq!(image.inner.version(), b"2");
+        assert_eq!(image.data, firmware);
+
+        // The progress is reported in the compressed firmware
+        let total = Some(compressed.len() as u32);
+        assert!(progress.0.iter().all(|(_, _, t)| *t == total));
+        let (offset, len, _) = progress.0.last().unwrap();
+        assert_eq!(offset + len, compressed.len() as u32);
+    }
+
+    #[tokio::test]
+    async fn test_update_compressed_checked() {
+        let firmware: Vec<u8> = (0..2000).map(|i| (i / 10) as u8).collect();
+        let compressed = compress(&firmware);
+
+        // The checksum of the update is verified against the decompressed firmware
+        if cfg!(feature = "sha256") {
+            let mut other = firmware.clone();
+            other[1000] ^= 1;
+            let service = InMemory::new(b"2", &other).with_compressed(Codec::Heatshrink, &compressed);
+            let mut device = Decompress::new(Image::new(b"1"));
+            let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default());
+            let result = updater.run(&mut device, &mut TokioDelay).await;
+            assert!(matches!(result, Err(Error::Device(DecompressError::ChecksumMismatch))));
+            assert_eq!(device.into_inner().inner.version(), b"1");
+        }
+
+        // The decompressed firmware is checked against the capacity of the device
+        assert!(compressed.len() < 1000);
+        let service = InMemory::new(b"2", &firmware).with_compressed(Codec::Heatshrink, &compressed);
+        let mut device = Decompress::new(Probe::<1000>::new(b"1"));
+        let mut updater = FirmwareUpdater::new(service, UpdaterConfig::default());
+        let result = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(
+            result,
+            Err(Error::Device(DecompressError::ImageTooLarge(end))) if end > 1000
+        ));
+    }
+}
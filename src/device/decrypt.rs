@@ -0,0 +1,650 @@
+use {
+    crate::{
+        protocol::{BootState, Bytes, Capabilities, FailureReport},
+        resume::{NoStore, ResumeStore, RESUME_RECORD_SIZE},
+        traits::{FirmwareDevice, FirmwareStatus, FirmwareVersion},
+    },
+    aes::{
+        cipher::{BlockEncrypt, InnerIvInit, KeyInit, StreamCipher, StreamCipherSeek},
+        Aes128,
+    },
+    ctr::{Ctr32BE, CtrCore},
+    ghash::{universal_hash::UniversalHash, GHash},
+    serde::{Deserialize, Serialize},
+};
+
+/// Size of the nonce at the start of an encrypted firmware.
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of the authentication tag at the end of an encrypted firmware.
+pub const TAG_SIZE: usize = 16;
+
+/// Size of the buffer used for writing decrypted firmware to the device.
+const CHUNK_SIZE: usize = 64;
+
+/// The error type of a `Decrypt` device.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecryptError<E> {
+    /// Error from the underlying device.
+    Device(E),
+    /// The authentication tag of the firmware is missing or does not verify.
+    Authentication,
+    /// A block was not written at the offset following the previous block, and cannot be decrypted.
+    OffsetMismatch {
+        /// The offset following the previous block.
+        expected: u32,
+        /// The offset of the block.
+        offset: u32,
+    },
+}
+
+/// A device adapter decrypting firmware encrypted with AES-128-GCM into the underlying device.
+///
+/// The encrypted firmware consists of the 12 byte nonce, the ciphertext and the 16 byte authentication tag, and has
+/// no associated data. The plaintext is passed to the underlying device as the ciphertext is received, and the
+/// firmware is only swapped if the authentication tag verifies.
+///
+/// With a `ResumeStore`, the progress of the decryption is stored whenever the updater stores a checkpoint, so
+/// that an update interrupted by a reset is resumed from the same offset as the updater. Otherwise, the update is
+/// restarted from the start.
+pub struct Decrypt<D, S = NoStore>
+where
+    D: FirmwareDevice,
+    S: ResumeStore,
+{
+    device: D,
+    cipher: Aes128,
+    store: S,
+    version: Option<D::Version>,
+    progress: Option<Progress>,
+}
+
+impl<D> Decrypt<D>
+where
+    D: FirmwareDevice,
+{
+    /// Create an adapter decrypting firmware with `key` into `device`.
+    pub fn new(device: D, key: &[u8; 16]) -> Self {
+        Self {
+            device,
+            cipher: Aes128::new(key.into()),
+            store: NoStore,
+            version: None,
+            progress: None,
+        }
+    }
+}
+
+impl<D, S> Decrypt<D, S>
+where
+    D: FirmwareDevice,
+    S: ResumeStore,
+{
+    /// Use the provided store for the progress of the decryption, so that an update interrupted by a reset can
+    /// be resumed.
+    pub fn with_store<S2: ResumeStore>(self, store: S2) -> Decrypt<D, S2> {
+        Decrypt {
+            device: self.device,
+            cipher: self.cipher,
+            store,
+            version: self.version,
+            progress: self.progress,
+        }
+    }
+
+    /// Return the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Encrypt a block of zeros, or the counter block `counter` of `nonce`.
+    fn encrypt(&self, nonce: Option<&[u8; NONCE_SIZE]>, counter: u32) -> [u8; 16] {
+        let mut block = [0; 16];
+        if let Some(nonce) = nonce {
+            block[..NONCE_SIZE].copy_from_slice(nonce);
+            block[NONCE_SIZE..].copy_from_slice(&counter.to_be_bytes());
+        }
+        let mut block = block.into();
+        self.cipher.encrypt_block(&mut block);
+        block.into()
+    }
+
+    /// Decrypt the next ciphertext of the firmware and write it to the device.
+    async fn decrypt(&mut self, progress: &mut Progress, ciphertext: &[u8]) -> Result<(), DecryptError<D::Error>> {
+        // The first counter block is used for the authentication tag
+        let mut iv = [0; 16];
+        iv[..NONCE_SIZE].copy_from_slice(&progress.nonce);
+        iv[NONCE_SIZE..].copy_from_slice(&2u32.to_be_bytes());
+        let mut ctr = Ctr32BE::from_core(CtrCore::inner_iv_init(self.cipher.clone(), &iv.into()));
+        ctr.seek(progress.ghash.len);
+        for chunk in ciphertext.chunks(CHUNK_SIZE) {
+            let offset = progress.ghash.len as u32;
+            progress.ghash.update(chunk);
+
+            let mut plaintext = [0; CHUNK_SIZE];
+            let plaintext = &mut plaintext[..chunk.len()];
+            plaintext.copy_from_slice(chunk);
+            ctr.apply_keystream(plaintext);
+            self.device
+                .write(offset, plaintext)
+                .await
+                .map_err(DecryptError::Device)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the progress of the decryption from the store, if it matches the version being written.
+    async fn restore(&mut self, version: Option<&[u8]>) -> Option<Progress> {
+        let mut record = [0; RESUME_RECORD_SIZE];
+        let len = match self.store.load(&mut record).await {
+            Ok(len) => len?,
+            Err(e) => {
+                warn!("Error loading decryption progress: {:?}", debug2format!(e));
+                return None;
+            }
+        };
+        let checkpoint: Checkpoint = postcard::from_bytes(&record[..len]).ok()?;
+        if version != Some(checkpoint.version.as_ref()) {
+            return None;
+        }
+        let len = checkpoint
+            .offset
+            .checked_sub((checkpoint.nonce.len() + checkpoint.held.len()) as u32)? as u64;
+        let ghash = Ghash::restore(&self.encrypt(None, 0), checkpoint.ghash, &checkpoint.block, len)?;
+        let mut progress = Progress::new(ghash);
+        progress.offset = checkpoint.offset;
+        progress.nonce_len = checkpoint.nonce.len();
+        progress
+            .nonce
+            .get_mut(..progress.nonce_len)?
+            .copy_from_slice(&checkpoint.nonce);
+        progress.held_len = checkpoint.held.len();
+        progress
+            .held
+            .get_mut(..progress.held_len)?
+            .copy_from_slice(&checkpoint.held);
+        Some(progress)
+    }
+
+    /// Restore the progress of the decryption at `offset` from the store, when the updater resumes from a
+    /// checkpoint older than the progress of the decryption.
+    async fn rewind(&mut self, offset: u32) -> Option<Progress> {
+        let version = self.version.clone()?;
+        let progress = self.restore(Some(version.as_ref())).await?;
+        if progress.offset != offset {
+            return None;
+        }
+        debug!("Rewinding decryption to offset {}", offset);
+        Some(progress)
+    }
+
+    /// Store the progress of the decryption.
+    async fn save(&mut self) {
+        let (Some(version), Some(progress)) = (&self.version, &self.progress) else {
+            return;
+        };
+        let pending = (progress.ghash.len % 16) as usize;
+        let checkpoint = Checkpoint {
+            version: Bytes::new(version.as_ref()),
+            offset: progress.offset,
+            nonce: Bytes::new(&progress.nonce[..progress.nonce_len]),
+            ghash: progress.ghash.state(),
+            block: Bytes::new(&progress.ghash.block[..pending]),
+            held: Bytes::new(&progress.held[..progress.held_len]),
+        };
+        let mut record = [0; RESUME_RECORD_SIZE];
+        let Ok(record) = postcard::to_slice(&checkpoint, &mut record) else {
+            warn!("Decryption progress does not fit in a record");
+            return;
+        };
+        if let Err(e) = self.store.store(record).await {
+            warn!("Error storing decryption progress: {:?}", debug2format!(e));
+        }
+    }
+
+    /// Remove the progress of the decryption from the store.
+    async fn forget(&mut self) {
+        self.progress = None;
+        if let Err(e) = self.store.clear().await {
+            warn!("Error clearing decryption progress: {:?}", debug2format!(e));
+        }
+    }
+}
+
+impl<D, S> FirmwareDevice for Decrypt<D, S>
+where
+    D: FirmwareDevice,
+    S: ResumeStore,
+{
+    const MTU: usize = D::MTU;
+    const CAPACITY: usize = D::CAPACITY.saturating_add(NONCE_SIZE + TAG_SIZE);
+    const CAPABILITIES: Capabilities = D::CAPABILITIES;
+    type Version = D::Version;
+    type Error = DecryptError<D::Error>;
+
+    async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        let mut status = self.device.status().await.map_err(DecryptError::Device)?;
+        if self.progress.is_none() {
+            let version = status.next_version.as_ref().map(|v| v.as_ref());
+            // The checkpoint is only usable if the device has written the firmware it covers
+            self.progress = self
+                .restore(version)
+                .await
+                .filter(|p| p.ghash.len <= status.next_offset as u64);
+            if let Some(progress) = &self.progress {
+                debug!("Resuming decryption at offset {}", progress.offset);
+            }
+        }
+        self.version = status.next_version.clone();
+        status.next_offset = self.progress.as_ref().map_or(0, |p| p.offset);
+        Ok(status)
+    }
+
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error> {
+        self.forget().await;
+        self.version = D::Version::from_slice(version).ok();
+        let size = size.map(|s| s.saturating_sub((NONCE_SIZE + TAG_SIZE) as u32));
+        self.device.start(version, size).await.map_err(DecryptError::Device)
+    }
+
+    async fn write(&mut self, offset: u32, mut data: &[u8]) -> Result<(), Self::Error> {
+        let mut progress = match self.progress.take() {
+            Some(progress) if offset != 0 && offset == progress.offset => progress,
+            _ if offset == 0 => Progress::new(Ghash::new(&self.encrypt(None, 0))),
+            progress => match self.rewind(offset).await {
+                Some(progress) => progress,
+                None => {
+                    let expected = progress.as_ref().map_or(0, |p| p.offset);
+                    self.progress = progress;
+                    warn!("Encrypted block at offset {}, expected {}", offset, expected);
+                    return Err(DecryptError::OffsetMismatch { expected, offset });
+                }
+            },
+        };
+        progress.offset = progress.offset.saturating_add(data.len() as u32);
+
+        while progress.nonce_len < NONCE_SIZE && !data.is_empty() {
+            progress.nonce[progress.nonce_len] = data[0];
+            progress.nonce_len += 1;
+            data = &data[1..];
+        }
+
+        // The last bytes received may be the authentication tag, and are held back until more data is received
+        let ready = (progress.held_len + data.len()).saturating_sub(TAG_SIZE);
+        let from_held = core::cmp::min(ready, progress.held_len);
+        let held = progress.held;
+        self.decrypt(&mut progress, &held[..from_held]).await?;
+        self.decrypt(&mut progress, &data[..ready - from_held]).await?;
+
+        let mut next = [0; TAG_SIZE];
+        let kept = progress.held_len - from_held;
+        next[..kept].copy_from_slice(&held[from_held..progress.held_len]);
+        let rest = &data[ready - from_held..];
+        next[kept..kept + rest.len()].copy_from_slice(rest);
+        progress.held = next;
+        progress.held_len = kept + rest.len();
+        self.progress.replace(progress);
+        Ok(())
+    }
+
+    async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
+        let Some(progress) = self.progress.take() else {
+            return Err(DecryptError::Authentication);
+        };
+        if progress.nonce_len < NONCE_SIZE || progress.held_len < TAG_SIZE {
+            warn!("Encrypted firmware is too short");
+            return Err(DecryptError::Authentication);
+        }
+        let mask = self.encrypt(Some(&progress.nonce), 1);
+        let tag = progress.ghash.finalize();
+        let diff = tag
+            .iter()
+            .zip(&mask)
+            .zip(&progress.held)
+            .fold(0, |acc, ((t, m), h)| acc | (t ^ m ^ h));
+        if diff != 0 {
+            warn!("Authentication tag of the firmware does not verify");
+            return Err(DecryptError::Authentication);
+        }
+        self.forget().await;
+        self.device
+            .update(version, checksum)
+            .await
+            .map_err(DecryptError::Device)
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        self.device.synced().await.map_err(DecryptError::Device)
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Self::Error> {
+        self.save().await;
+        self.device.checkpoint().await.map_err(DecryptError::Device)
+    }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        self.forget().await;
+        self.device.abort().await.map_err(DecryptError::Device)
+    }
+
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        self.device.boot_state().await.map_err(DecryptError::Device)
+    }
+
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        self.device.mark_booted().await.map_err(DecryptError::Device)
+    }
+
+    fn error_code(error: &Self::Error) -> u32 {
+        match error {
+            DecryptError::Device(e) => D::error_code(e),
+            DecryptError::Authentication => FailureReport::AUTHENTICATION_FAILED,
+            DecryptError::OffsetMismatch { .. } => FailureReport::OFFSET_MISMATCH,
+        }
+    }
+}
+
+/// The progress of decrypting a firmware.
+struct Progress {
+    /// The number of bytes of the encrypted firmware received.
+    offset: u32,
+    nonce: [u8; NONCE_SIZE],
+    nonce_len: usize,
+    /// The digest of the ciphertext decrypted so far.
+    ghash: Ghash,
+    /// The last bytes received, which are the authentication tag if no more data follows.
+    held: [u8; TAG_SIZE],
+    held_len: usize,
+}
+
+impl Progress {
+    fn new(ghash: Ghash) -> Self {
+        Self {
+            offset: 0,
+            nonce: [0; NONCE_SIZE],
+            nonce_len: 0,
+            ghash,
+            held: [0; TAG_SIZE],
+            held_len: 0,
+        }
+    }
+}
+
+/// The progress of decrypting a firmware, as stored in a `ResumeStore`.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<'a> {
+    #[serde(borrow)]
+    version: Bytes<'a>,
+    offset: u32,
+    #[serde(borrow)]
+    nonce: Bytes<'a>,
+    ghash: [u8; 16],
+    #[serde(borrow)]
+    block: Bytes<'a>,
+    #[serde(borrow)]
+    held: Bytes<'a>,
+}
+
+/// The GHASH function of AES-GCM over the ciphertext, without associated data.
+///
+/// The last full block of ciphertext is only hashed once the next block is complete, so that the state can be
+/// saved as the block which, hashed from the initial state, gives the state after the last full block.
+struct Ghash {
+    hash: GHash,
+    /// The last full block, not hashed yet.
+    last: [u8; 16],
+    /// The partial block following the last full block.
+    block: [u8; 16],
+    len: u64,
+}
+
+impl Ghash {
+    fn new(h: &[u8; 16]) -> Self {
+        Self {
+            hash: <GHash as ghash::universal_hash::KeyInit>::new(h.into()),
+            last: [0; 16],
+            block: [0; 16],
+            len: 0,
+        }
+    }
+
+    /// Restore the hash of `len` bytes from its saved state and partial block.
+    fn restore(h: &[u8; 16], state: [u8; 16], block: &[u8], len: u64) -> Option<Self> {
+        let mut ghash = Self::new(h);
+        let pending = (len % 16) as usize;
+        ghash.block[..pending].copy_from_slice(block.get(..pending)?);
+        ghash.last = state;
+        ghash.len = len;
+        Some(ghash)
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let pos = (self.len % 16) as usize;
+            let n = core::cmp::min(16 - pos, data.len());
+            self.block[pos..pos + n].copy_from_slice(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+            if pos + n == 16 {
+                self.hash.update(&[self.last.into()]);
+                self.last = self.block;
+            }
+        }
+    }
+
+    /// The state to save for restoring the hash.
+    fn state(&self) -> [u8; 16] {
+        let mut state: [u8; 16] = self.hash.clone().finalize().into();
+        for (s, l) in state.iter_mut().zip(&self.last) {
+            *s ^= l;
+        }
+        state
+    }
+
+    fn finalize(mut self) -> [u8; 16] {
+        self.hash.update(&[self.last.into()]);
+        let pending = (self.len % 16) as usize;
+        self.hash.update_padded(&self.block[..pending]);
+        let mut lengths = [0; 16];
+        lengths[8..].copy_from_slice(&(self.len * 8).to_be_bytes());
+        self.hash.update(&[lengths.into()]);
+        self.hash.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use {
+        super::*,
+        crate::{
+            device::testing::{Image, TokioDelay},
+            protocol::{Command, Status},
+            service::InMemory,
+            DeviceStatus, Error, FirmwareUpdater, MemoryStore, UpdateService, UpdaterConfig,
+        },
+        aes_gcm::{aead::Aead, Aes128Gcm},
+        std::vec::Vec,
+    };
+
+    const KEY: [u8; 16] = [7; 16];
+
+    fn encrypt(firmware: &[u8]) -> Vec<u8> {
+        let nonce = [3; NONCE_SIZE];
+        let cipher = <Aes128Gcm as aes_gcm::KeyInit>::new(&KEY.into());
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(cipher.encrypt(&nonce.into(), firmware).unwrap());
+        encrypted
+    }
+
+    #[tokio::test]
+    async fn test_update_encrypted() {
+        let firmware: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+        let encrypted = encrypt(&firmware);
+
+        let mut device = Decrypt::new(Image::new(b"1"), &KEY);
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &encrypted), UpdaterConfig::default());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        let image = device.into_inner();
+        assert_eq!(image.inner.version(), b"2");
+        assert_eq!(image.data, firmware);
+
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        let mut device = Decrypt::new(Image::new(b"1"), &KEY);
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &tampered), UpdaterConfig::default());
+        let result = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(result, Err(Error::Device(DecryptError::Authentication))));
+        assert_eq!(device.into_inner().inner.version(), b"1");
+    }
+
+    #[tokio::test]
+    async fn test_resume_encrypted() {
+        let firmware: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+        let encrypted = encrypt(&firmware);
+
+        for (split, interval) in [
+            (1, 1),
+            (5, 1),
+            (12, 1),
+            (20, 1),
+            (100, 1),
+            (999, 1),
+            (1020, 1),
+            (100, 4),
+            (999, 4),
+        ] {
+            let mut store = MemoryStore::new();
+            let mut device = Decrypt::new(Image::new(b"1"), &KEY).with_store(&mut store);
+            device.status().await.unwrap();
+            device.start(b"2", Some(encrypted.len() as u32)).await.unwrap();
+            let blocks = encrypted[..split].chunks(7).count();
+            for (i, block) in encrypted[..split].chunks(7).enumerate() {
+                device.write(i as u32 * 7, block).await.unwrap();
+                if (i + 1) % interval == 0 {
+                    device.checkpoint().await.unwrap();
+                }
+            }
+
+            // Reset the adapter, keeping the written firmware and the store
+            let mut device = Decrypt::new(device.into_inner(), &KEY).with_store(&mut store);
+            let offset = device.status().await.unwrap().next_offset;
+            let checkpoint = blocks / interval * interval;
+            assert_eq!(offset as usize, core::cmp::min(checkpoint * 7, split));
+            for (i, block) in encrypted[offset as usize..].chunks(64).enumerate() {
+                device.write(offset + i as u32 * 64, block).await.unwrap();
+            }
+            device.update(b"2", &[]).await.unwrap();
+            assert_eq!(device.into_inner().data, firmware);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_encrypted_updater() {
+        /// An update service never responding after sending the given number of blocks, as if the device was reset.
+        struct Stall<'a> {
+            inner: InMemory<'a>,
+            blocks: usize,
+        }
+
+        impl<'a> UpdateService for Stall<'a> {
+            type Error = core::convert::Infallible;
+
+            async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                let command = self.inner.request(status).await?;
+                if let Command::Write { .. } = command {
+                    if self.blocks == 0 {
+                        core::future::pending::<()>().await;
+                    }
+                    self.blocks -= 1;
+                }
+                Ok(command)
+            }
+        }
+
+        let firmware: Vec<u8> = (0..3000).map(|i| (i * 3) as u8).collect();
+        let encrypted = encrypt(&firmware);
+        let config = || UpdaterConfig {
+            checkpoint_interval: 3,
+            ..UpdaterConfig::default()
+        };
+
+        // The updater resumes from its last checkpoint, both after a reset of the adapter and when the adapter has
+        // decrypted past the checkpoint
+        for (blocks, reset) in [(2, true), (4, true), (7, true), (4, false), (7, false)] {
+            let mut updater_store = MemoryStore::new();
+            let mut store = MemoryStore::new();
+            let mut device = Decrypt::new(Image::new(b"1"), &KEY).with_store(&mut store);
+            {
+                let service = Stall {
+                    inner: InMemory::new(b"2", &encrypted),
+                    blocks,
+                };
+                let mut updater = FirmwareUpdater::new(service, config()).with_store(&mut updater_store);
+                let mut delay = TokioDelay;
+                let run = updater.run(&mut device, &mut delay);
+                let result = tokio::time::timeout(tokio::time::Duration::from_millis(100), run).await;
+                assert!(result.is_err());
+            }
+            let written = device.device.data.len();
+            assert!(written > 0);
+
+            let mut device = if reset {
+                Decrypt::new(device.into_inner(), &KEY).with_store(&mut store)
+            } else {
+                device
+            };
+            let mut updater =
+                FirmwareUpdater::new(InMemory::new(b"2", &encrypted), config()).with_store(&mut updater_store);
+            let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+            assert_eq!(status, DeviceStatus::Updated);
+            let image = device.into_inner();
+            assert_eq!(image.inner.version(), b"2");
+            assert_eq!(image.data, firmware);
+        }
+
+        // Without a store, the updater restarts the transfer
+        let mut device = Decrypt::new(Image::new(b"1"), &KEY);
+        {
+            let service = Stall {
+                inner: InMemory::new(b"2", &encrypted),
+                blocks: 4,
+            };
+            let mut updater = FirmwareUpdater::new(service, config());
+            let mut delay = TokioDelay;
+            let run = updater.run(&mut device, &mut delay);
+            let result = tokio::time::timeout(tokio::time::Duration::from_millis(100), run).await;
+            assert!(result.is_err());
+        }
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &encrypted), config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.into_inner().data, firmware);
+    }
+
+    #[tokio::test]
+    async fn test_offset_mismatch() {
+        let firmware: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+        let encrypted = encrypt(&firmware);
+
+        let mut device = Decrypt::new(Image::new(b"1"), &KEY);
+        device.status().await.unwrap();
+        device.start(b"2", Some(encrypted.len() as u32)).await.unwrap();
+        device.write(0, &encrypted[..64]).await.unwrap();
+        let result = device.write(128, &encrypted[128..192]).await;
+        assert!(matches!(
+            result,
+            Err(DecryptError::OffsetMismatch {
+                expected: 64,
+                offset: 128
+            })
+        ));
+
+        // The decryption continues from the expected offset
+        device.write(64, &encrypted[64..]).await.unwrap();
+        device.update(b"2", &[]).await.unwrap();
+        assert_eq!(device.into_inner().data, firmware);
+    }
+}
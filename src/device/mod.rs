@@ -1,5 +1,21 @@
 //! Implementations of the `FirmwareDevice` trait.
+mod decompress;
 mod serial;
 mod simulator;
 
-pub use {serial::*, simulator::*};
+pub use {decompress::*, serial::*, simulator::*};
+
+#[cfg(feature = "aes")]
+mod decrypt;
+
+#[cfg(feature = "aes")]
+pub use decrypt::*;
+
+#[cfg(feature = "manifest")]
+mod components;
+
+#[cfg(feature = "manifest")]
+pub use components::*;
+
+#[cfg(test)]
+pub(crate) mod testing;
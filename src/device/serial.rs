@@ -3,12 +3,13 @@ use {
         protocol::*,
         traits::{FirmwareDevice, FirmwareStatus},
     },
+    embedded_io::ReadExactError,
     embedded_io_async::{Read, Write},
     heapless::Vec,
-    postcard::{from_bytes, to_slice},
+    postcard::to_slice,
 };
 
-/// Defines a fixed frame protocol based on types
+/// Defines a fixed frame protocol based on types, see `service::FRAME_SIZE`
 const FRAME_SIZE: usize = 1024;
 
 /// A FirmwareDevice based on a fixed-frame serial protocol, using `postcard` as the serialization format.
@@ -18,6 +19,8 @@ where
     T: Read + Write,
 {
     status: FirmwareStatus<Vec<u8, 16>>,
+    protocol: Option<Protocol>,
+    boot: Option<BootState>,
     transport: T,
     buf: [u8; FRAME_SIZE],
 }
@@ -36,8 +39,15 @@ where
                 next_version: None,
                 next_offset: 0,
             },
+            protocol: None,
+            boot: None,
         }
     }
+
+    /// Returns true if the device on the other end of the transport supports the given capabilities.
+    fn supports(&self, capabilities: Capabilities) -> bool {
+        self.protocol.is_some_and(|p| p.capabilities.contains(capabilities))
+    }
 }
 
 /// Errors returned by Serial
@@ -49,6 +59,17 @@ pub enum SerialError<T, C> {
     Codec(C),
     /// Other internal error.
     Other,
+    /// The transport ended before a full frame was received.
+    Truncated,
+}
+
+impl<T, C> From<ReadExactError<T>> for SerialError<T, C> {
+    fn from(error: ReadExactError<T>) -> Self {
+        match error {
+            ReadExactError::UnexpectedEof => SerialError::Truncated,
+            ReadExactError::Other(e) => SerialError::Transport(e),
+        }
+    }
 }
 
 impl<T> FirmwareDevice for Serial<T>
@@ -60,14 +81,12 @@ where
     type Error = SerialError<T::Error, postcard::Error>;
 
     async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
-        let _ = self
-            .transport
-            .read(&mut self.buf)
-            .await
-            .map_err(SerialError::Transport)?;
+        self.transport.read_exact(&mut self.buf).await?;
 
-        let status: Status = from_bytes(&self.buf).map_err(SerialError::Codec)?;
+        let status = Status::from_frame(&self.buf).map_err(SerialError::Codec)?;
         self.status.current_version = Vec::from_slice(&status.version).map_err(|_| SerialError::Other)?;
+        self.protocol = status.protocol;
+        self.boot = status.boot;
         if let Some(update) = status.update {
             self.status.next_offset = update.offset;
             self.status
@@ -77,32 +96,73 @@ where
         Ok(self.status.clone())
     }
 
-    async fn start(&mut self, version: &[u8]) -> Result<(), Self::Error> {
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error> {
         self.status.next_offset = 0;
         self.status
             .next_version
             .replace(Vec::from_slice(version).map_err(|_| SerialError::Other)?);
+        // Devices predating the Start command are prepared by the first block written
+        if let Some(size) = size.filter(|_| self.supports(Capabilities::START)) {
+            let command: Command = Command::new_start(version, size, None);
+            self.buf.fill(0);
+            to_slice(&command, &mut self.buf).map_err(SerialError::Codec)?;
+            self.transport
+                .write_all(&self.buf)
+                .await
+                .map_err(SerialError::Transport)?;
+        }
         Ok(())
     }
 
     async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
         let command: Command = Command::new_write(self.status.next_version.as_ref().unwrap(), offset, data, None);
+        self.buf.fill(0);
         to_slice(&command, &mut self.buf).map_err(SerialError::Codec)?;
-        let _ = self.transport.write(&self.buf).await.map_err(SerialError::Transport)?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(SerialError::Transport)?;
         Ok(())
     }
 
     async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
         let command: Command = Command::new_swap(version, checksum, None);
+        self.buf.fill(0);
         to_slice(&command, &mut self.buf).map_err(SerialError::Codec)?;
-        let _ = self.transport.write(&self.buf).await.map_err(SerialError::Transport)?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(SerialError::Transport)?;
         Ok(())
     }
 
     async fn synced(&mut self) -> Result<(), Self::Error> {
         let command: Command = Command::new_sync(&self.status.current_version, None, None);
+        self.buf.fill(0);
         to_slice(&command, &mut self.buf).map_err(SerialError::Codec)?;
-        let _ = self.transport.write(&self.buf).await.map_err(SerialError::Transport)?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(SerialError::Transport)?;
         Ok(())
     }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        self.status.next_offset = 0;
+        self.status.next_version = None;
+        if self.supports(Capabilities::ABORT) {
+            let command: Command = Command::new_abort(None, None);
+            self.buf.fill(0);
+            to_slice(&command, &mut self.buf).map_err(SerialError::Codec)?;
+            self.transport
+                .write_all(&self.buf)
+                .await
+                .map_err(SerialError::Transport)?;
+        }
+        Ok(())
+    }
+
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        Ok(self.boot.unwrap_or(BootState::Confirmed))
+    }
 }
@@ -1,12 +1,19 @@
 use {
-    crate::traits::{FirmwareDevice, FirmwareStatus},
+    crate::{
+        blocking,
+        traits::{FirmwareDevice, FirmwareStatus},
+    },
     core::convert::Infallible,
     heapless::Vec,
 };
 
-/// A simulated device which implements the `FirmwareDevice` trait.
+/// A simulated device which implements the `FirmwareDevice` trait, and its blocking variant.
+///
+/// The simulator keeps track of the firmware being written, so that an interrupted update can be resumed.
 pub struct Simulator {
     version: Vec<u8, 16>,
+    next_version: Option<Vec<u8, 16>>,
+    next_offset: u32,
 }
 
 impl Simulator {
@@ -14,6 +21,8 @@ impl Simulator {
     pub fn new(version: &[u8]) -> Self {
         Self {
             version: Vec::from_slice(version).unwrap(),
+            next_version: None,
+            next_offset: 0,
         }
     }
 
@@ -21,6 +30,11 @@ impl Simulator {
     pub fn version(&self) -> &[u8] {
         &self.version[..]
     }
+
+    /// Return the offset written of the firmware being written.
+    pub fn next_offset(&self) -> u32 {
+        self.next_offset
+    }
 }
 
 impl FirmwareDevice for Simulator {
@@ -29,32 +43,74 @@ impl FirmwareDevice for Simulator {
     type Error = Infallible;
 
     async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        blocking::FirmwareDevice::status(self)
+    }
+
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error> {
+        blocking::FirmwareDevice::start(self, version, size)
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        blocking::FirmwareDevice::write(self, offset, data)
+    }
+
+    async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
+        blocking::FirmwareDevice::update(self, version, checksum)
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        blocking::FirmwareDevice::synced(self)
+    }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        blocking::FirmwareDevice::abort(self)
+    }
+}
+
+impl blocking::FirmwareDevice for Simulator {
+    const MTU: usize = 256;
+    type Version = Vec<u8, 16>;
+    type Error = Infallible;
+
+    fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
         debug!("Simulator::status()");
         Ok(FirmwareStatus {
             current_version: self.version.clone(),
-            next_offset: 0,
-            next_version: None,
+            next_offset: self.next_offset,
+            next_version: self.next_version.clone(),
         })
     }
 
-    async fn start(&mut self, _version: &[u8]) -> Result<(), Self::Error> {
+    fn start(&mut self, version: &[u8], _size: Option<u32>) -> Result<(), Self::Error> {
         debug!("Simulator::start()");
+        self.next_version = Some(Vec::from_slice(version).unwrap());
+        self.next_offset = 0;
         Ok(())
     }
 
-    async fn write(&mut self, _offset: u32, _data: &[u8]) -> Result<(), Self::Error> {
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
         debug!("Simulator::write()");
+        self.next_offset = offset + data.len() as u32;
         Ok(())
     }
 
-    async fn update(&mut self, version: &[u8], _checksum: &[u8]) -> Result<(), Self::Error> {
+    fn update(&mut self, version: &[u8], _checksum: &[u8]) -> Result<(), Self::Error> {
         debug!("Simulator::update()");
         self.version = Vec::from_slice(version).unwrap();
+        self.next_version = None;
+        self.next_offset = 0;
         Ok(())
     }
 
-    async fn synced(&mut self) -> Result<(), Self::Error> {
+    fn synced(&mut self) -> Result<(), Self::Error> {
         debug!("Simulator::synced()");
         Ok(())
     }
+
+    fn abort(&mut self) -> Result<(), Self::Error> {
+        debug!("Simulator::abort()");
+        self.next_version = None;
+        self.next_offset = 0;
+        Ok(())
+    }
 }
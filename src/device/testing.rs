@@ -0,0 +1,151 @@
+//! Devices and delays shared by the tests of the device adapters.
+extern crate std;
+use {
+    super::Simulator,
+    crate::{
+        protocol::{BootState, FailureReport},
+        traits::{FirmwareDevice, FirmwareStatus},
+    },
+    core::convert::Infallible,
+    std::vec::Vec,
+};
+
+pub(crate) struct TokioDelay;
+
+impl embedded_hal_async::delay::DelayNs for TokioDelay {
+    async fn delay_ns(&mut self, i: u32) {
+        tokio::time::sleep(tokio::time::Duration::from_nanos(i as u64)).await;
+    }
+
+    async fn delay_ms(&mut self, i: u32) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(i as u64)).await;
+    }
+}
+
+/// A simulated device keeping the written firmware.
+pub(crate) struct Image {
+    pub(crate) inner: Simulator,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Image {
+    pub(crate) fn new(version: &[u8]) -> Self {
+        Self {
+            inner: Simulator::new(version),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl FirmwareDevice for Image {
+    const MTU: usize = Simulator::MTU;
+    type Version = <Simulator as FirmwareDevice>::Version;
+    type Error = Infallible;
+
+    async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        self.inner.status().await
+    }
+
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error> {
+        self.data.clear();
+        self.inner.start(version, size).await
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        assert!(offset as usize <= self.data.len());
+        self.data.truncate(offset as usize);
+        self.data.extend_from_slice(data);
+        self.inner.write(offset, data).await
+    }
+
+    async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
+        self.inner.update(version, checksum).await
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        self.inner.synced().await
+    }
+}
+
+/// A simulated device recording the calls of the updater, with hooks for injecting the behaviour of a real device.
+pub(crate) struct Probe<const CAPACITY: usize = { usize::MAX }> {
+    pub(crate) inner: Simulator,
+    /// The boot state reported to the updater.
+    pub(crate) boot: BootState,
+    /// Writes never complete once this number of writes has been made, as if the device was reset.
+    pub(crate) hang_after: Option<usize>,
+    /// Writes at the given offset fail with the given error.
+    pub(crate) fail_at: Option<(u32, u32)>,
+    pub(crate) starts: usize,
+    pub(crate) writes: usize,
+    pub(crate) aborted: bool,
+    pub(crate) marked: bool,
+}
+
+impl<const CAPACITY: usize> Probe<CAPACITY> {
+    pub(crate) fn new(version: &[u8]) -> Self {
+        Self {
+            inner: Simulator::new(version),
+            boot: BootState::Confirmed,
+            hang_after: None,
+            fail_at: None,
+            starts: 0,
+            writes: 0,
+            aborted: false,
+            marked: false,
+        }
+    }
+}
+
+impl<const CAPACITY: usize> FirmwareDevice for Probe<CAPACITY> {
+    const MTU: usize = Simulator::MTU;
+    const CAPACITY: usize = CAPACITY;
+    type Version = <Simulator as FirmwareDevice>::Version;
+    type Error = u32;
+
+    async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error> {
+        self.inner.status().await.map_err(|e| match e {})
+    }
+
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error> {
+        self.starts += 1;
+        self.inner.start(version, size).await.map_err(|e| match e {})
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        if self.hang_after == Some(self.writes) {
+            core::future::pending::<()>().await;
+        }
+        if let Some((_, error)) = self.fail_at.filter(|(at, _)| *at == offset) {
+            return Err(error);
+        }
+        self.writes += 1;
+        self.inner.write(offset, data).await.map_err(|e| match e {})
+    }
+
+    async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error> {
+        self.inner.update(version, checksum).await.map_err(|e| match e {})
+    }
+
+    async fn synced(&mut self) -> Result<(), Self::Error> {
+        self.inner.synced().await.map_err(|e| match e {})
+    }
+
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        self.aborted = true;
+        self.inner.abort().await.map_err(|e| match e {})
+    }
+
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        Ok(self.boot)
+    }
+
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        self.marked = true;
+        Ok(())
+    }
+
+    fn error_code(error: &Self::Error) -> u32 {
+        FailureReport::DEVICE_SPECIFIC + error
+    }
+}
@@ -7,9 +7,9 @@ compile_error!("You may not enable both `defmt` and `log` features.");
 macro_rules! assert {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::assert!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::assert!($($x)*);
         }
     };
@@ -18,9 +18,9 @@ macro_rules! assert {
 macro_rules! assert_eq {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::assert_eq!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::assert_eq!($($x)*);
         }
     };
@@ -29,9 +29,9 @@ macro_rules! assert_eq {
 macro_rules! assert_ne {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::assert_ne!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::assert_ne!($($x)*);
         }
     };
@@ -40,9 +40,9 @@ macro_rules! assert_ne {
 macro_rules! debug_assert {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::debug_assert!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::debug_assert!($($x)*);
         }
     };
@@ -51,9 +51,9 @@ macro_rules! debug_assert {
 macro_rules! debug_assert_eq {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::debug_assert_eq!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::debug_assert_eq!($($x)*);
         }
     };
@@ -62,9 +62,9 @@ macro_rules! debug_assert_eq {
 macro_rules! debug_assert_ne {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::debug_assert_ne!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::debug_assert_ne!($($x)*);
         }
     };
@@ -73,9 +73,9 @@ macro_rules! debug_assert_ne {
 macro_rules! todo {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::todo!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::todo!($($x)*);
         }
     };
@@ -84,9 +84,9 @@ macro_rules! todo {
 macro_rules! unreachable {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::unreachable!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::unreachable!($($x)*);
         }
     };
@@ -95,9 +95,9 @@ macro_rules! unreachable {
 macro_rules! panic {
     ($($x:tt)*) => {
         {
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(any(test, not(feature = "defmt")))]
             ::core::panic!($($x)*);
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(test)))]
             ::defmt::panic!($($x)*);
         }
     };
@@ -168,6 +168,21 @@ macro_rules! error {
     };
 }
 
+/// Format a value only implementing `Debug` in a log message, which defmt requires wrapping in `Debug2Format`.
+#[cfg(feature = "defmt")]
+macro_rules! debug2format {
+    ($x:expr) => {
+        ::defmt::Debug2Format(&$x)
+    };
+}
+
+#[cfg(not(feature = "defmt"))]
+macro_rules! debug2format {
+    ($x:expr) => {
+        &$x
+    };
+}
+
 #[cfg(feature = "defmt")]
 macro_rules! unwrap {
     ($($x:tt)*) => {
@@ -195,12 +210,12 @@ macro_rules! unwrap {
     }
 }
 
-#[cfg(feature = "defmt-timestamp-uptime")]
-defmt::timestamp! {"{=u64:us}", crate::time::Instant::now().as_micros() }
-
+// Used by `unwrap!`, which is only expanded when the crate unwraps
+#[allow(dead_code)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct NoneError;
 
+#[allow(dead_code)]
 pub trait Try {
     type Ok;
     type Error;
@@ -1,5 +1,4 @@
 #![cfg_attr(not(test), no_std)]
-#![cfg_attr(feature = "nightly", feature(async_fn_in_trait))]
 #![doc = include_str!("../README.md")]
 #![deny(missing_docs)]
 
@@ -8,20 +7,45 @@ mod fmt;
 mod protocol;
 pub use protocol::*;
 
-#[cfg(feature = "nightly")]
-pub mod device;
+mod observer;
+pub use observer::*;
+
+mod retry;
+pub use retry::RetryPolicy;
+
+mod signature;
+pub use signature::*;
+
+mod clock;
+pub use clock::*;
+
+mod policy;
+pub use policy::*;
+
+mod checksum;
+#[cfg(feature = "sha256")]
+pub use checksum::{checksum, CHECKSUM_SIZE};
+
+mod delta;
+pub use delta::{FirmwareReader, NoReader};
 
-#[cfg(feature = "nightly")]
+pub mod blocking;
+pub mod device;
 pub mod service;
 
-#[cfg(feature = "nightly")]
-mod traits;
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(feature = "manifest")]
+pub use manifest::*;
+
+mod resume;
+pub use resume::*;
 
-#[cfg(feature = "nightly")]
+mod traits;
 pub use traits::*;
 
-#[cfg(feature = "nightly")]
 mod updater;
-
-#[cfg(feature = "nightly")]
 pub use updater::*;
+
+mod machine;
+pub use machine::*;
@@ -0,0 +1,1136 @@
+use {
+    crate::{
+        checksum::{Checksum, STATE_SIZE},
+        clock::{Clock, NoClock},
+        delta::{FirmwareReader, Patch},
+        policy::{AcceptAll, UpdatePolicy},
+        protocol::{BootState, Bytes, Capabilities, Codec, Command, FailureReport, FailureStage, Status},
+        resume::Checkpoint,
+        retry::Backoff,
+        signature::{NoVerifier, SignatureVerifier},
+        traits::{FirmwareDevice, FirmwareStatus, FirmwareVersion},
+        updater::{DeviceStatus, Error, Failure, UpdaterConfig, STAGED_SIZE},
+    },
+    heapless::Vec,
+};
+
+/// The maximum number of actions performed in response to a single command.
+const MAX_ACTIONS: usize = 3;
+
+/// The properties of a device that are advertised to the update service and checked by an `UpdateMachine`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceProfile {
+    /// The preferred block size of the device.
+    pub mtu: usize,
+    /// The maximum size of firmware that can be written to the device.
+    pub capacity: usize,
+    /// The optional protocol features supported by the device. Patches are only accepted if the capabilities
+    /// contain `Capabilities::DELTA`, and are then applied with `UpdateMachine::apply_patch`.
+    pub capabilities: Capabilities,
+}
+
+impl DeviceProfile {
+    /// Return the profile of a `FirmwareDevice`.
+    pub fn of<F: FirmwareDevice>() -> Self {
+        Self {
+            mtu: F::MTU,
+            capacity: F::CAPACITY,
+            capabilities: F::CAPABILITIES,
+        }
+    }
+
+    /// The capabilities advertised to the update service, including the features implemented by the updater.
+    fn advertised(&self) -> Capabilities {
+        Capabilities::DEFAULT.union(self.capabilities)
+    }
+}
+
+/// An action to perform in response to a command from the update service.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action<'m> {
+    /// Prepare the device for writing a new firmware version with `FirmwareDevice::start`, and clear any
+    /// checkpoint of the previous update.
+    Start {
+        /// The version to write.
+        version: &'m [u8],
+        /// The total size of the firmware, if known.
+        size: Option<u32>,
+    },
+    /// Write a block of firmware to the device.
+    Write {
+        /// The offset of the block.
+        offset: u32,
+        /// The data of the block.
+        data: &'m [u8],
+        /// The codec the block is compressed with, to be written with `FirmwareDevice::write_compressed`.
+        codec: Option<Codec>,
+    },
+    /// Apply a block of a patch against the current firmware with `UpdateMachine::apply_patch`.
+    Patch {
+        /// The data of the block.
+        data: &'m [u8],
+    },
+    /// Store a checkpoint of the update progress, encoded with `UpdateMachine::checkpoint`.
+    Checkpoint,
+    /// Mark the running firmware as booted with `FirmwareDevice::mark_booted`.
+    MarkBooted,
+    /// Mark the device as being in sync with `FirmwareDevice::synced`.
+    Synced,
+    /// Swap to the written firmware with `FirmwareDevice::update`, and clear the checkpoint. The firmware has
+    /// already been verified.
+    Swap {
+        /// The version to swap to.
+        version: &'m [u8],
+        /// The checksum of the firmware.
+        checksum: &'m [u8],
+    },
+    /// The written firmware has been verified, and is staged instead of swapped to, since `deferred_swap` is
+    /// configured. The checksum is kept for swapping to the firmware with `FirmwareDevice::update` later.
+    Stage {
+        /// The version of the staged firmware.
+        version: &'m [u8],
+        /// The checksum of the firmware.
+        checksum: &'m [u8],
+    },
+    /// Discard the firmware being written with `FirmwareDevice::abort`, and clear the checkpoint.
+    Abort {
+        /// The reason for aborting sent by the update service.
+        reason: Option<u32>,
+    },
+    /// Wait for the given number of milliseconds before sending the next status, as instructed by the update
+    /// service.
+    Wait(u32),
+    /// Wait before retrying a failed request.
+    Retry {
+        /// The number of consecutive failed requests.
+        failures: u32,
+        /// The delay in milliseconds before sending the next status.
+        delay_ms: u32,
+    },
+    /// The update is finished, and no more status updates are sent.
+    Done(DeviceStatus),
+}
+
+/// The actions to perform, in order, in response to a command from the update service. Once they are performed,
+/// the next status is sent to the update service, unless the last action is `Action::Done`.
+pub struct Actions<'m> {
+    actions: [Option<Action<'m>>; MAX_ACTIONS],
+    len: usize,
+    next: usize,
+}
+
+impl<'m> Actions<'m> {
+    fn new() -> Self {
+        Self {
+            actions: [None, None, None],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, action: Action<'m>) {
+        self.actions[self.len] = Some(action);
+        self.len += 1;
+    }
+}
+
+impl<'m> Iterator for Actions<'m> {
+    type Item = Action<'m>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let action = self.actions.get_mut(self.next)?.take();
+        self.next += 1;
+        action
+    }
+}
+
+/// The state machine of the update protocol, for driving an update with a transport and timing of the caller's
+/// choosing, such as a scheduler, a low-power sleep or the duty cycle of a radio.
+///
+/// The caller sends the `status` to the update service, passes the response to `handle`, and performs the returned
+/// actions on the device until an `Action::Done`. If an action fails, the caller records it with `failed`, and
+/// sends the failure `report` to the update service. `FirmwareUpdater::run` is built on the state machine.
+pub struct UpdateMachine<F, V = NoVerifier, C = NoClock, A = AcceptAll>
+where
+    F: FirmwareVersion,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+{
+    pub(crate) config: UpdaterConfig,
+    profile: DeviceProfile,
+    verifier: V,
+    clock: C,
+    policy: A,
+    pub(crate) correlation_id: u32,
+    pub(crate) backoff: Backoff,
+    state: UpdaterState<F>,
+    operation: Option<Operation<F>>,
+    device_offset: u32,
+    mismatches: u32,
+    written: u32,
+    failures: u32,
+    estimated_ms: u32,
+    start_ms: u64,
+}
+
+#[derive(Clone)]
+struct UpdaterState<F>
+where
+    F: FirmwareVersion,
+{
+    current_version: F,
+    next_offset: u32,
+    next_version: Option<F>,
+    total: Option<u32>,
+    checksum: Checksum,
+    boot: BootState,
+    patch: Option<Patch>,
+    staged: Option<Staged>,
+    /// Whether the device has been prepared for writing the next version by this state machine.
+    started: bool,
+    /// Whether the next version has been accepted by the update policy of this state machine.
+    accepted: bool,
+    /// Whether the next version is written from compressed blocks, whose checksum is verified by the device.
+    compressed: bool,
+}
+
+/// A firmware verified and staged, waiting for the application to swap to it.
+#[derive(Clone)]
+pub(crate) struct Staged {
+    pub(crate) version: Vec<u8, STAGED_SIZE>,
+    pub(crate) checksum: Vec<u8, STAGED_SIZE>,
+}
+
+impl<F> UpdaterState<F>
+where
+    F: FirmwareVersion,
+{
+    /// Returns true if the given version is the version currently being written.
+    fn is_writing(&self, version: &[u8]) -> bool {
+        self.next_version
+            .as_ref()
+            .map(|v| v.as_ref() == version)
+            .unwrap_or(false)
+    }
+
+    /// The offset reported to the update service, in the patch being applied or else in the firmware being written.
+    fn offset(&self) -> u32 {
+        self.patch.as_ref().map_or(self.next_offset, Patch::offset)
+    }
+}
+
+impl<F> UpdateMachine<F>
+where
+    F: FirmwareVersion,
+{
+    /// Create a state machine for updating a device with the given profile, from the status and boot state
+    /// reported by the device.
+    pub fn new(config: UpdaterConfig, profile: DeviceProfile, status: FirmwareStatus<F>, boot: BootState) -> Self {
+        let checksum = Checksum::new();
+        // The blocks written before are only kept if their digest is restored from a checkpoint
+        let next_offset = if checksum.can_resume(status.next_offset) {
+            status.next_offset
+        } else {
+            0
+        };
+        Self {
+            config,
+            profile,
+            verifier: NoVerifier,
+            clock: NoClock,
+            policy: AcceptAll,
+            correlation_id: 0,
+            backoff: Backoff::new(0),
+            state: UpdaterState {
+                current_version: status.current_version,
+                next_offset,
+                next_version: status.next_version,
+                total: None,
+                checksum,
+                boot,
+                patch: None,
+                staged: None,
+                started: false,
+                accepted: false,
+                compressed: false,
+            },
+            operation: None,
+            device_offset: status.next_offset,
+            mismatches: 0,
+            written: 0,
+            failures: 0,
+            estimated_ms: 0,
+            start_ms: 0,
+        }
+    }
+}
+
+impl<F, V, C, A> UpdateMachine<F, V, C, A>
+where
+    F: FirmwareVersion,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+{
+    /// Use the provided verifier, like `FirmwareUpdater::with_verifier`.
+    pub fn with_verifier<V2: SignatureVerifier>(self, verifier: V2) -> UpdateMachine<F, V2, C, A> {
+        UpdateMachine {
+            config: self.config,
+            profile: self.profile,
+            verifier,
+            clock: self.clock,
+            policy: self.policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            state: self.state,
+            operation: self.operation,
+            device_offset: self.device_offset,
+            mismatches: self.mismatches,
+            written: self.written,
+            failures: self.failures,
+            estimated_ms: self.estimated_ms,
+            start_ms: self.start_ms,
+        }
+    }
+
+    /// Use the provided clock for measuring the time elapsed since the state machine was created with this call.
+    pub fn with_clock<C2: Clock>(self, mut clock: C2) -> UpdateMachine<F, V, C2, A> {
+        let start_ms = clock.now_ms();
+        UpdateMachine {
+            config: self.config,
+            profile: self.profile,
+            verifier: self.verifier,
+            clock,
+            policy: self.policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            state: self.state,
+            operation: self.operation,
+            device_offset: self.device_offset,
+            mismatches: self.mismatches,
+            written: self.written,
+            failures: self.failures,
+            estimated_ms: self.estimated_ms,
+            start_ms,
+        }
+    }
+
+    /// Use the provided policy, like `FirmwareUpdater::with_policy`.
+    pub fn with_policy<A2: UpdatePolicy>(self, policy: A2) -> UpdateMachine<F, V, C, A2> {
+        UpdateMachine {
+            config: self.config,
+            profile: self.profile,
+            verifier: self.verifier,
+            clock: self.clock,
+            policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            state: self.state,
+            operation: self.operation,
+            device_offset: self.device_offset,
+            mismatches: self.mismatches,
+            written: self.written,
+            failures: self.failures,
+            estimated_ms: self.estimated_ms,
+            start_ms: self.start_ms,
+        }
+    }
+
+    /// Seed the jitter of the retry policy, like `FirmwareUpdater::with_seed`.
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.backoff = Backoff::new(seed);
+        self
+    }
+
+    /// Seed the correlation ids and the jitter of the retry policy, like `FirmwareUpdater::with_rng`.
+    #[cfg(feature = "rand_core")]
+    pub fn with_rng<G: rand_core::RngCore>(mut self, rng: &mut G) -> Self {
+        self.correlation_id = rng.next_u32();
+        self.backoff = Backoff::new(rng.next_u32());
+        self
+    }
+
+    /// Restore the progress of the firmware being written from a checkpoint record. The checkpoint is only used if
+    /// the device reports writing the same version, and to have written at least up to the checkpoint.
+    pub fn resume(&mut self, record: &[u8]) {
+        match Checkpoint::decode(record) {
+            Some(checkpoint)
+                if self.state.is_writing(&checkpoint.version) && checkpoint.offset <= self.device_offset =>
+            {
+                if let Some(checksum) =
+                    Checksum::restore(&checkpoint.checksum).filter(|c| c.can_resume(checkpoint.offset))
+                {
+                    debug!("Resuming update from checkpoint at offset {}", checkpoint.offset);
+                    self.state.next_offset = checkpoint.offset;
+                    self.state.total = checkpoint.total;
+                    self.state.checksum = checksum;
+                }
+            }
+            _ => debug!("Ignoring checkpoint not matching the firmware being written"),
+        }
+    }
+
+    /// Encode a checkpoint of the progress of the firmware being written into `buf`, which should hold
+    /// `RESUME_RECORD_SIZE` bytes, returning the record to store.
+    pub fn checkpoint<'b>(&self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let version = self.state.next_version.as_ref()?;
+        let mut checksum = [0; STATE_SIZE];
+        let len = self.state.checksum.save(&mut checksum)?;
+        let checkpoint = Checkpoint {
+            version: Bytes::new(version.as_ref()),
+            offset: self.state.next_offset,
+            total: self.state.total,
+            checksum: Bytes::new(&checksum[..len]),
+        };
+        let record = checkpoint.encode(buf);
+        if record.is_none() {
+            warn!("Checkpoint does not fit in a record");
+        }
+        record
+    }
+
+    /// Restore a firmware verified and staged by a previous run of the state machine, if the device still reports
+    /// writing the same version. Returns false if the staged firmware is no longer being written.
+    pub fn restage(&mut self, version: &[u8], checksum: &[u8]) -> bool {
+        if !self.state.is_writing(version) {
+            debug!("Discarding staged firmware not matching the firmware being written");
+            return false;
+        }
+        let (Ok(version), Ok(checksum)) = (Vec::from_slice(version), Vec::from_slice(checksum)) else {
+            return false;
+        };
+        self.state.next_offset = self.device_offset;
+        self.state.staged = Some(Staged { version, checksum });
+        true
+    }
+
+    /// Take the firmware staged by the state machine, to be swapped to or restored by the next run.
+    pub(crate) fn take_staged(&mut self) -> Option<Staged> {
+        self.state.staged.take()
+    }
+
+    /// The total size of the firmware being written, if known.
+    pub fn total(&self) -> Option<u32> {
+        self.state.total
+    }
+
+    /// Return the next status to send to the update service.
+    pub fn status(&mut self) -> Status<'_> {
+        let correlation_id = self.next_correlation_id();
+        describe(&self.state, &self.profile, correlation_id)
+    }
+
+    /// Like `status`, but describing a copy of the state, so that the response can be handled while the status
+    /// is borrowed.
+    pub(crate) fn request(&mut self) -> Request<F> {
+        Request {
+            correlation_id: self.next_correlation_id(),
+            state: self.state.clone(),
+            profile: self.profile,
+        }
+    }
+
+    /// Handle the response of the update service to the last status, returning the actions to perform.
+    ///
+    /// Failed requests are retried after a delay, until the configured number of consecutive failures or the
+    /// deadline is exceeded. Once the deadline is exceeded, the update fails even if the request succeeded.
+    pub fn handle<'m, D, S>(&mut self, response: Result<Command<'m>, Failure<S>>) -> Result<Actions<'m>, Error<D, S>> {
+        let command = match response {
+            Ok(command) if command.correlation_id().unwrap_or(self.correlation_id) != self.correlation_id => {
+                debug!(
+                    "Dropping response with correlation id {:?}, expected {}",
+                    command.correlation_id(),
+                    self.correlation_id
+                );
+                return self.retry(Failure::CorrelationId);
+            }
+            Ok(command) => command,
+            Err(failure) => return self.retry(failure),
+        };
+        if self.expired() {
+            warn!("Giving up after the deadline for the update");
+            return Err(Error::RetriesExhausted(Failure::Timeout));
+        }
+        self.failures = 0;
+        self.backoff.reset();
+
+        let mut actions = Actions::new();
+        match command {
+            Command::Write {
+                version, offset, data, ..
+            } => self.write(&mut actions, version.data(), offset, data.data(), None)?,
+            Command::WriteCompressed {
+                version,
+                offset,
+                data,
+                codec,
+                ..
+            } => {
+                if !self.profile.capabilities.contains(codec.capability()) {
+                    warn!("Compressed block with unsupported codec {:?}", codec);
+                    return Err(Error::UnsupportedCodec(codec));
+                }
+                // Compressed blocks are written like plain blocks, at offsets and with a size in the compressed
+                // firmware
+                self.write(&mut actions, version.data(), offset, data.data(), Some(codec))?
+            }
+            Command::Patch {
+                version, offset, data, ..
+            } => self.patch(&mut actions, version.data(), offset, data.data())?,
+            Command::Start { version, size, .. } => self.start(&mut actions, version.data(), Some(size))?,
+            Command::Sync { version, poll, .. } => {
+                if version.as_ref() != self.state.current_version.as_ref() {
+                    warn!(
+                        "Sync for version {:?} not matching the device version",
+                        version.as_ref()
+                    );
+                    // The update service expects the device to have swapped to the version
+                    self.operation
+                        .replace(Operation::new(FailureStage::Swap, version.as_ref()));
+                    return Err(Error::VersionMismatch);
+                }
+                self.operation = None;
+                self.state.staged = None;
+                if self.state.boot == BootState::Trial {
+                    debug!("Marking firmware as booted");
+                    actions.push(Action::MarkBooted);
+                }
+                debug!("Device firmware is up to date");
+                actions.push(Action::Synced);
+                // Without a poll interval, the device is synced again after the initial retry delay, as for Wait
+                let poll = poll
+                    .filter(|p| *p > 0)
+                    .unwrap_or(self.config.retry.initial_delay_ms / 1000);
+                actions.push(Action::Done(DeviceStatus::Synced(Some(poll))));
+            }
+            Command::Wait { .. } if self.state.staged.is_some() => {
+                debug!("Staged firmware is waiting to be swapped to");
+                actions.push(self.ready());
+            }
+            Command::Wait { poll, .. } => {
+                debug!("Instruction to wait for {:?} seconds", poll);
+                let delay_ms = poll
+                    .filter(|p| *p > 0)
+                    .map(|p| p.saturating_mul(1000))
+                    .unwrap_or(self.config.retry.initial_delay_ms);
+                self.estimated_ms = self.estimated_ms.saturating_add(delay_ms);
+                actions.push(Action::Wait(delay_ms));
+            }
+            Command::Swap { version, checksum, .. } => {
+                self.swap(&mut actions, version.data(), checksum.data(), None)?
+            }
+            Command::SignedSwap {
+                version,
+                checksum,
+                signature,
+                ..
+            } => self.swap(&mut actions, version.data(), checksum.data(), Some(signature.data()))?,
+            Command::Abort { reason, .. } => {
+                self.operation = None;
+                debug!("Update aborted by the update service, reason {:?}", reason);
+                self.state.next_version = None;
+                self.state.next_offset = 0;
+                self.state.total = None;
+                self.state.checksum = Checksum::new();
+                self.state.patch = None;
+                self.state.staged = None;
+                self.state.started = false;
+                self.state.accepted = false;
+                actions.push(Action::Abort { reason });
+                actions.push(Action::Done(DeviceStatus::Aborted(reason)));
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Apply a block of the patch being received, as instructed by an `Action::Patch`, writing the produced
+    /// firmware to the device. Returns the offset and length of the firmware written.
+    pub async fn apply_patch<D: FirmwareDevice, R: FirmwareReader, S>(
+        &mut self,
+        device: &mut D,
+        reader: &mut R,
+        data: &[u8],
+    ) -> Result<(u32, u32), Error<D::Error, S>> {
+        let state = &mut self.state;
+        let start = state.next_offset;
+        let Some(patch) = &mut state.patch else {
+            return Err(Error::InvalidPatch);
+        };
+        patch
+            .apply(device, reader, &mut state.checksum, &mut state.next_offset, data)
+            .await?;
+        Ok((start, state.next_offset - start))
+    }
+
+    /// Record that performing an action failed, so that the failure is reported for the stage of the action.
+    pub fn failed(&mut self, action: &Action<'_>) {
+        self.operation = match action {
+            Action::Start { version, .. } => Some(Operation::new(FailureStage::Start, version)),
+            Action::Write { .. } | Action::Patch { .. } => self.state.next_version.clone().map(|version| Operation {
+                stage: FailureStage::Write,
+                version: Some(version),
+            }),
+            Action::Swap { version, .. } | Action::Stage { version, .. } => {
+                Some(Operation::new(FailureStage::Swap, version))
+            }
+            Action::MarkBooted | Action::Synced | Action::Abort { .. } => None,
+            Action::Checkpoint | Action::Wait(_) | Action::Retry { .. } | Action::Done(_) => return,
+        };
+    }
+
+    /// Return the failure report to send to the update service when the update fails with the given error, or
+    /// `None` if the error should not be reported. Device errors are reported with the code returned by
+    /// `device_code`.
+    pub fn report<D, S>(&mut self, error: &Error<D, S>, device_code: impl FnOnce(&D) -> u32) -> Option<Status<'_>> {
+        let code = match error {
+            Error::Device(e) => device_code(e),
+            Error::DecodeVersion => FailureReport::DECODE_VERSION,
+            Error::VersionMismatch => FailureReport::VERSION_MISMATCH,
+            Error::OffsetMismatch { .. } => FailureReport::OFFSET_MISMATCH,
+            Error::ImageTooLarge(_) => FailureReport::IMAGE_TOO_LARGE,
+            Error::ChecksumMismatch => FailureReport::CHECKSUM_MISMATCH,
+            Error::InvalidSignature => FailureReport::INVALID_SIGNATURE,
+            Error::InvalidPatch => FailureReport::INVALID_PATCH,
+            Error::UnsupportedCodec(_) => FailureReport::UNSUPPORTED_CODEC,
+            Error::Rejected => FailureReport::REJECTED,
+            Error::Delay | Error::Service(_) | Error::RetriesExhausted(_) => return None,
+        };
+        self.operation.as_ref()?;
+        let correlation_id = self.next_correlation_id();
+        let operation = self.operation.as_ref()?;
+        let version = operation.version.as_ref().map(|v| v.as_ref()).unwrap_or(&[]);
+        Some(
+            Status::failed(
+                self.state.current_version.as_ref(),
+                Some(self.profile.mtu as u32),
+                FailureReport::new(operation.stage, code, version),
+                Some(correlation_id),
+            )
+            .with_capabilities(self.profile.advertised())
+            .with_boot(self.state.boot),
+        )
+    }
+
+    /// Return the correlation id to use for the next status update.
+    fn next_correlation_id(&mut self) -> u32 {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        self.correlation_id
+    }
+
+    /// Count a failed request, returning the delay before retrying it, or an error if no more retries are allowed.
+    fn retry<'m, D, S>(&mut self, failure: Failure<S>) -> Result<Actions<'m>, Error<D, S>> {
+        if let Failure::Timeout = failure {
+            self.estimated_ms = self.estimated_ms.saturating_add(self.config.timeout_ms);
+        }
+        self.failures += 1;
+        let exhausted = self
+            .config
+            .max_consecutive_failures
+            .is_some_and(|max| self.failures > max)
+            || self.expired();
+        if exhausted {
+            warn!("Giving up after {} consecutive failed requests", self.failures);
+            return Err(Error::RetriesExhausted(failure));
+        }
+        let delay_ms = self.backoff.next(&self.config.retry);
+        self.estimated_ms = self.estimated_ms.saturating_add(delay_ms);
+        let mut actions = Actions::new();
+        actions.push(Action::Retry {
+            failures: self.failures,
+            delay_ms,
+        });
+        Ok(actions)
+    }
+
+    /// Returns true if the deadline for the update has passed.
+    fn expired(&mut self) -> bool {
+        self.config
+            .deadline_ms
+            .is_some_and(|deadline| self.elapsed_ms() > deadline)
+    }
+
+    /// Return the time elapsed since the start of the update, as measured by the clock, or the estimated time if
+    /// the state machine has no clock.
+    fn elapsed_ms(&mut self) -> u32 {
+        if C::ENABLED {
+            self.clock
+                .now_ms()
+                .saturating_sub(self.start_ms)
+                .try_into()
+                .unwrap_or(u32::MAX)
+        } else {
+            self.estimated_ms
+        }
+    }
+
+    /// Prepare for writing a new firmware version from offset 0.
+    fn start<'m, D, S>(
+        &mut self,
+        actions: &mut Actions<'m>,
+        version: &'m [u8],
+        size: Option<u32>,
+    ) -> Result<(), Error<D, S>> {
+        self.operation.replace(Operation::new(FailureStage::Start, version));
+        if let Some(size) = size {
+            if size as usize > self.profile.capacity {
+                warn!("Firmware of {} bytes does not fit in device", size);
+                return Err(Error::ImageTooLarge(size));
+            }
+        }
+        self.state.accepted = false;
+        self.accept(version)?;
+        debug!(
+            "Updating device firmware from {:?} to {:?}",
+            self.state.current_version, version
+        );
+        self.state
+            .next_version
+            .replace(F::from_slice(version).map_err(|_| Error::DecodeVersion)?);
+        self.state.checksum.reset();
+        self.state.next_offset = 0;
+        self.state.total = size;
+        self.state.patch = None;
+        self.state.staged = None;
+        self.state.compressed = false;
+        self.state.started = true;
+        actions.push(Action::Start { version, size });
+        Ok(())
+    }
+
+    /// Check the version to write against the update policy, unless it has already been accepted. A version left in
+    /// progress by an earlier run is checked before it is continued.
+    fn accept<D, S>(&mut self, version: &[u8]) -> Result<(), Error<D, S>> {
+        if self.state.accepted {
+            return Ok(());
+        }
+        if !self.policy.accept(self.state.current_version.as_ref(), version) {
+            warn!("Firmware version {:?} rejected by the update policy", version);
+            return Err(Error::Rejected);
+        }
+        self.state.accepted = true;
+        Ok(())
+    }
+
+    /// Handle a block of firmware, restarting the update if the block is for another version.
+    fn write<'m, D, S>(
+        &mut self,
+        actions: &mut Actions<'m>,
+        version: &'m [u8],
+        offset: u32,
+        data: &'m [u8],
+        codec: Option<Codec>,
+    ) -> Result<(), Error<D, S>> {
+        // Writing from offset 0 over blocks left by an earlier run requires preparing the device again
+        let writing = self.state.is_writing(version) && self.state.patch.is_none();
+        if !writing || (offset == 0 && (self.state.next_offset != 0 || !self.state.started)) {
+            self.start(actions, version, None)?;
+        } else if offset == 0 {
+            self.state.checksum.reset();
+        }
+        self.operation.replace(Operation::new(FailureStage::Write, version));
+        self.accept(version)?;
+
+        if offset != 0 && !writing {
+            debug!("Write for a different version at offset {}, restarting at 0", offset);
+        } else if offset != self.state.next_offset {
+            self.unexpected(self.state.next_offset, offset)?;
+        } else {
+            // The device checks the size of decompressed firmware against its capacity
+            let end = offset.saturating_add(data.len() as u32);
+            if codec.is_none() && end as usize > self.profile.capacity {
+                warn!("Firmware does not fit in device");
+                return Err(Error::ImageTooLarge(end));
+            }
+            actions.push(Action::Write { offset, data, codec });
+            self.state.checksum.update(data);
+            self.state.compressed = codec.is_some();
+            self.state.next_offset = end;
+            self.mismatches = 0;
+            self.written += 1;
+            if self.config.checkpoint_interval > 0 && self.written % self.config.checkpoint_interval == 0 {
+                actions.push(Action::Checkpoint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a block of a patch, restarting the update if the patch cannot be continued.
+    fn patch<'m, D, S>(
+        &mut self,
+        actions: &mut Actions<'m>,
+        version: &'m [u8],
+        offset: u32,
+        data: &'m [u8],
+    ) -> Result<(), Error<D, S>> {
+        if !self.profile.capabilities.contains(Capabilities::DELTA) {
+            warn!("Patch received for a device that cannot read back its firmware");
+            return Err(Error::InvalidPatch);
+        }
+        // A patch can only be continued from the state it was left in by this updater
+        let writing = self.state.is_writing(version);
+        let restart = !writing
+            || (offset == 0 && (self.state.offset() != 0 || !self.state.started))
+            || (offset != 0 && self.state.patch.is_none());
+        if restart {
+            self.start(actions, version, None)?;
+            self.state.patch.replace(Patch::new());
+        } else if offset == 0 {
+            self.state.checksum.reset();
+            self.state.next_offset = 0;
+            self.state.patch.replace(Patch::new());
+        }
+        self.operation.replace(Operation::new(FailureStage::Write, version));
+        self.accept(version)?;
+
+        if offset != 0 && restart {
+            debug!("Patch for an unknown state at offset {}, restarting at 0", offset);
+        } else if offset != self.state.offset() {
+            self.unexpected(self.state.offset(), offset)?;
+        } else if self.state.patch.is_some() {
+            actions.push(Action::Patch { data });
+            self.mismatches = 0;
+        }
+        Ok(())
+    }
+
+    /// Verify the written firmware before swapping to it.
+    fn swap<'m, D, S>(
+        &mut self,
+        actions: &mut Actions<'m>,
+        version: &'m [u8],
+        checksum: &'m [u8],
+        signature: Option<&[u8]>,
+    ) -> Result<(), Error<D, S>> {
+        self.operation.replace(Operation::new(FailureStage::Swap, version));
+        if !self.state.is_writing(version) {
+            warn!("Swap for version {:?} not being written", version);
+            return Err(Error::VersionMismatch);
+        }
+        if self.state.staged.is_some() {
+            // The firmware was verified when it was staged
+            actions.push(self.ready());
+            return Ok(());
+        }
+        self.accept(version)?;
+        self.operation.replace(Operation::new(FailureStage::Verify, version));
+        // The checksum of compressed firmware covers the decompressed firmware, and is verified by the device
+        let written = (!self.state.compressed).then_some(&self.state.checksum);
+        verify(written, checksum, signature, self.config.public_key, &mut self.verifier)?;
+        if self.config.deferred_swap {
+            let (Ok(staged), Ok(digest)) = (Vec::from_slice(version), Vec::from_slice(checksum)) else {
+                warn!("Version or checksum of firmware {:?} too large to be staged", version);
+                return Err(Error::DecodeVersion);
+            };
+            debug!("Staging firmware");
+            self.state.staged = Some(Staged {
+                version: staged,
+                checksum: digest,
+            });
+            actions.push(Action::Stage { version, checksum });
+            return Ok(());
+        }
+        debug!("Swaping firmware");
+        self.operation.replace(Operation::new(FailureStage::Swap, version));
+        actions.push(Action::Swap { version, checksum });
+        actions.push(Action::Done(DeviceStatus::Updated));
+        Ok(())
+    }
+
+    /// The action finishing the update with the staged firmware waiting to be swapped to.
+    fn ready<'m>(&self) -> Action<'m> {
+        let version = self
+            .state
+            .staged
+            .as_ref()
+            .map(|s| s.version.clone())
+            .unwrap_or_default();
+        Action::Done(DeviceStatus::ReadyToSwap(version))
+    }
+
+    /// Count a block received at an unexpected offset. Duplicate blocks are dropped and gaps are re-requested from
+    /// the expected offset, until too many blocks in a row were at an unexpected offset.
+    fn unexpected<D, S>(&mut self, expected: u32, offset: u32) -> Result<(), Error<D, S>> {
+        debug!(
+            "Unexpected block at offset {}, expected {}. {}",
+            offset,
+            expected,
+            if offset < expected {
+                "Dropping duplicate"
+            } else {
+                "Re-requesting gap"
+            }
+        );
+        self.mismatches += 1;
+        if self.mismatches > self.config.max_offset_mismatches {
+            warn!("Too many blocks at unexpected offsets, giving up");
+            return Err(Error::OffsetMismatch { expected, offset });
+        }
+        Ok(())
+    }
+}
+
+/// A status to send to the update service, describing a copy of the state of an `UpdateMachine`.
+pub(crate) struct Request<F>
+where
+    F: FirmwareVersion,
+{
+    correlation_id: u32,
+    state: UpdaterState<F>,
+    profile: DeviceProfile,
+}
+
+impl<F> Request<F>
+where
+    F: FirmwareVersion,
+{
+    pub(crate) fn status(&self) -> Status<'_> {
+        describe(&self.state, &self.profile, self.correlation_id)
+    }
+}
+
+/// Describe the state of the update in a status update.
+fn describe<'a, F: FirmwareVersion>(
+    state: &'a UpdaterState<F>,
+    profile: &DeviceProfile,
+    correlation_id: u32,
+) -> Status<'a> {
+    let mtu = Some(profile.mtu as u32);
+    let status = match &state.next_version {
+        Some(next) => Status::update(
+            state.current_version.as_ref(),
+            mtu,
+            state.offset(),
+            next.as_ref(),
+            Some(correlation_id),
+        ),
+        None => Status::first(state.current_version.as_ref(), mtu, Some(correlation_id)),
+    }
+    .with_capabilities(profile.advertised())
+    .with_boot(state.boot);
+    match state.staged {
+        Some(_) => status.with_staged(),
+        None => status,
+    }
+}
+
+/// The stage of the update in progress, and the version being updated to.
+struct Operation<V> {
+    stage: FailureStage,
+    version: Option<V>,
+}
+
+impl<V> Operation<V>
+where
+    V: FirmwareVersion,
+{
+    fn new(stage: FailureStage, version: &[u8]) -> Self {
+        Self {
+            stage,
+            version: V::from_slice(version).ok(),
+        }
+    }
+}
+
+/// Verify the written firmware against the checksum, unless left to the device, and, if a public key is configured,
+/// the signature.
+fn verify<V: SignatureVerifier, D, S>(
+    written: Option<&Checksum>,
+    checksum: &[u8],
+    signature: Option<&[u8]>,
+    public_key: Option<&[u8]>,
+    verifier: &mut V,
+) -> Result<(), Error<D, S>> {
+    if written.is_some_and(|w| !w.verify(checksum)) {
+        warn!("Firmware checksum mismatch, refusing to swap");
+        return Err(Error::ChecksumMismatch);
+    }
+    if let Some(public_key) = public_key {
+        match signature {
+            Some(signature) if verifier.verify(public_key, checksum, signature) => {}
+            _ => {
+                warn!("Firmware signature invalid, refusing to swap");
+                return Err(Error::InvalidSignature);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            device::Simulator,
+            service::{InMemory, FRAME_SIZE},
+            traits::UpdateService,
+            RetryPolicy,
+        },
+        postcard::{from_bytes, to_slice},
+    };
+
+    async fn machine(device: &mut Simulator) -> UpdateMachine<<Simulator as FirmwareDevice>::Version> {
+        let status = device.status().await.unwrap();
+        let config = UpdaterConfig {
+            retry: RetryPolicy {
+                initial_delay_ms: 10,
+                multiplier: 2,
+                max_delay_ms: 40,
+                jitter_ms: 0,
+            },
+            max_consecutive_failures: Some(1),
+            ..Default::default()
+        };
+        UpdateMachine::new(config, DeviceProfile::of::<Simulator>(), status, BootState::Confirmed)
+    }
+
+    #[tokio::test]
+    async fn test_step_update() {
+        let firmware = [1; 1024];
+        let mut service = InMemory::new(b"2", &firmware);
+        let mut device = Simulator::new(b"1");
+        let mut machine = machine(&mut device).await;
+
+        // The status and command are passed through frames, as a transport of the caller would
+        let (mut tx, mut rx) = ([0; FRAME_SIZE], [0; FRAME_SIZE]);
+        let (mut started, mut written) = (0, 0);
+        let status = 'update: loop {
+            let len = to_slice(&machine.status(), &mut tx).unwrap().len();
+            let status: Status = from_bytes(&tx[..len]).unwrap();
+            let command = service.request(&status).await.unwrap();
+            let len = to_slice(&command, &mut rx).unwrap().len();
+            let command: Command = from_bytes(&rx[..len]).unwrap();
+
+            for action in machine.handle::<(), ()>(Ok(command)).unwrap() {
+                match action {
+                    Action::Start { version, size } => {
+                        device.start(version, size).await.unwrap();
+                        started += 1;
+                    }
+                    Action::Write { offset, data, codec } => {
+                        assert_eq!(codec, None);
+                        device.write(offset, data).await.unwrap();
+                        written += data.len();
+                    }
+                    Action::Swap { version, checksum } => device.update(version, checksum).await.unwrap(),
+                    Action::Done(status) => break 'update status,
+                    action => panic!("Unexpected action {:?}", action),
+                }
+            }
+        };
+
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(started, 1);
+        assert_eq!(written, firmware.len());
+        assert_eq!(machine.total(), Some(firmware.len() as u32));
+        assert_eq!(device.version(), b"2");
+    }
+
+    #[tokio::test]
+    async fn test_step_retry() {
+        let mut device = Simulator::new(b"1");
+        let mut machine = machine(&mut device).await;
+
+        let id = machine.status().correlation_id;
+        let mut actions = machine.handle::<(), ()>(Ok(Command::new_sync(b"1", None, id))).unwrap();
+        assert_eq!(actions.next(), Some(Action::Synced));
+        assert_eq!(actions.next(), Some(Action::Done(DeviceStatus::Synced(Some(0)))));
+        assert_eq!(actions.next(), None);
+
+        // A response to another status is retried like a failed request
+        let id = machine.status().correlation_id.unwrap();
+        let mut actions = machine
+            .handle::<(), ()>(Ok(Command::new_wait(None, Some(id + 1))))
+            .unwrap();
+        assert_eq!(
+            actions.next(),
+            Some(Action::Retry {
+                failures: 1,
+                delay_ms: 10
+            })
+        );
+        assert_eq!(actions.next(), None);
+
+        machine.status();
+        let result = machine.handle::<(), ()>(Err(Failure::Timeout));
+        assert!(matches!(result, Err(Error::RetriesExhausted(Failure::Timeout))));
+    }
+
+    #[tokio::test]
+    async fn test_step_report() {
+        let mut device = Simulator::new(b"1");
+        let mut machine = machine(&mut device).await;
+
+        let id = machine.status().correlation_id;
+        let actions: heapless::Vec<_, MAX_ACTIONS> = machine
+            .handle::<(), ()>(Ok(Command::new_write(b"2", 0, &[1; 16], id)))
+            .unwrap()
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                Action::Start {
+                    version: b"2",
+                    size: None
+                },
+                Action::Write {
+                    offset: 0,
+                    data: &[1; 16],
+                    codec: None
+                }
+            ]
+        );
+
+        // The failure is reported for the stage of the action that failed
+        machine.failed(&actions[0]);
+        let status = machine.report(&Error::<u32, ()>::Device(42), |e| *e).unwrap();
+        let failure = status.failure.unwrap();
+        assert_eq!(failure.stage, FailureStage::Start);
+        assert_eq!(failure.code, 42);
+        assert_eq!(failure.version, b"2");
+
+        assert!(machine.report(&Error::<u32, ()>::Delay, |e| *e).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_step_resume_policy() {
+        let mut device = Simulator::new(b"1.0");
+        let mut machine = machine(&mut device).await;
+        let id = machine.status().correlation_id;
+        machine
+            .handle::<(), ()>(Ok(Command::new_write(b"0.9", 0, &[1; 16], id)))
+            .unwrap()
+            .for_each(drop);
+        let mut buf = [0; crate::RESUME_RECORD_SIZE];
+        let record = machine.checkpoint(&mut buf).unwrap();
+
+        device.start(b"0.9", None).await.unwrap();
+        device.write(0, &[1; 16]).await.unwrap();
+
+        // The downgrade left in progress by an earlier run is rejected by the policy of the next run
+        let status = device.status().await.unwrap();
+        let mut resumed = UpdateMachine::new(
+            machine.config.clone(),
+            DeviceProfile::of::<Simulator>(),
+            status,
+            BootState::Confirmed,
+        )
+        .with_policy(crate::Semver);
+        resumed.resume(record);
+        let status = resumed.status();
+        assert_eq!(status.update.as_ref().map(|u| u.offset), Some(16));
+
+        let id = status.correlation_id;
+        let result = resumed.handle::<(), ()>(Ok(Command::new_write(b"0.9", 16, &[1; 16], id)));
+        assert!(matches!(result, Err(Error::Rejected)));
+        let status = resumed.report(&Error::<(), ()>::Rejected, |_| 0).unwrap();
+        assert_eq!(status.failure.unwrap().code, FailureReport::REJECTED);
+
+        let id = resumed.status().correlation_id;
+        let result = resumed.handle::<(), ()>(Ok(Command::new_swap(b"0.9", &[], id)));
+        assert!(matches!(result, Err(Error::Rejected)));
+    }
+}
@@ -0,0 +1,171 @@
+use {
+    crate::{
+        protocol::{BootState, Bytes, FailureReport},
+        traits::FirmwareVersion,
+    },
+    heapless::Vec,
+    serde::{Deserialize, Serialize},
+    serde_cbor::ser::SliceWrite,
+};
+
+/// The maximum number of components listed in a `Manifest`.
+pub const MAX_COMPONENTS: usize = 8;
+
+/// The maximum size in bytes of an encoded `Manifest`.
+pub const MANIFEST_SIZE: usize = 512;
+
+/// A manifest listing the images of the components of a device, such as an application core, a network core and
+/// a modem, that are updated together.
+///
+/// A multi-component firmware starts with the size of the encoded manifest as a little endian `u32`, followed by
+/// the manifest and the images of the components in the order they are listed. Like the manifests of IETF SUIT,
+/// the manifest is encoded in CBOR, as maps keyed by the integer index of each field, but it is not wrapped in a
+/// SUIT envelope: it is authenticated along with the images by the checksum or signature of the firmware.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest<'a> {
+    /// The version of the firmware as a whole, which must be the version of the update.
+    #[serde(borrow)]
+    pub version: Bytes<'a>,
+    /// The components listed in the manifest.
+    #[serde(borrow)]
+    pub components: Vec<Component<'a>, MAX_COMPONENTS>,
+}
+
+/// A component listed in a `Manifest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Component<'a> {
+    /// The index of the component in the `ComponentDevice`.
+    pub index: u8,
+    /// The version of the image.
+    #[serde(borrow)]
+    pub version: Bytes<'a>,
+    /// The size of the image.
+    pub size: u32,
+    /// The SHA-256 digest of the image.
+    #[serde(borrow)]
+    pub digest: Bytes<'a>,
+}
+
+impl<'a> Manifest<'a> {
+    /// Create a manifest without components.
+    pub fn new(version: &'a [u8]) -> Self {
+        Self {
+            version: Bytes::new(version),
+            components: Vec::new(),
+        }
+    }
+
+    /// Encode the manifest into `buf`, returning the encoded manifest.
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let mut serializer = serde_cbor::Serializer::new(SliceWrite::new(buf)).packed_format();
+        self.serialize(&mut serializer).ok()?;
+        let writer = serializer.into_inner();
+        let len = writer.bytes_written();
+        Some(&writer.into_inner()[..len])
+    }
+
+    /// Decode a manifest.
+    pub fn decode(data: &'a [u8]) -> Option<Self> {
+        serde_cbor::de::from_slice_with_scratch(data, &mut []).ok()
+    }
+}
+
+impl<'a> Component<'a> {
+    /// Create a component entry for the image of the component at `index`.
+    pub fn new(index: u8, version: &'a [u8], size: u32, digest: &'a [u8]) -> Self {
+        Self {
+            index,
+            version: Bytes::new(version),
+            size,
+            digest: Bytes::new(digest),
+        }
+    }
+}
+
+/// Represents a device made of several components with their own images, that can be updated by a
+/// `FirmwareUpdater` through the `Components` device adapter.
+#[allow(async_fn_in_trait)]
+pub trait ComponentDevice {
+    /// The preferred block size to be passed in write.
+    const MTU: usize;
+
+    /// The expected version type for this device.
+    type Version: FirmwareVersion;
+
+    /// The error type.
+    type Error;
+
+    /// Return the version of the firmware as a whole, the version of the last manifest installed.
+    async fn version(&mut self) -> Result<Self::Version, Self::Error>;
+
+    /// Return the version of the image running on a component.
+    async fn component_version(&mut self, component: u8) -> Result<Self::Version, Self::Error>;
+
+    /// Prepare for writing a new image of the given size to a component.
+    async fn start(&mut self, component: u8, version: &[u8], size: u32) -> Result<(), Self::Error>;
+
+    /// Write a block of the image of a component at the expected offset.
+    async fn write(&mut self, component: u8, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Swap the images written to all started components at once, and record `version` as the version of the
+    /// firmware as a whole. Only called once the digest of every image has been verified.
+    async fn update(&mut self, version: &[u8]) -> Result<(), Self::Error>;
+
+    /// Mark firmware as being in sync with the expected
+    async fn synced(&mut self) -> Result<(), Self::Error>;
+
+    /// Discard the images being written, after the update service aborted the update.
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the boot state of the running firmware.
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        Ok(BootState::Confirmed)
+    }
+
+    /// Mark the running firmware of all components as good.
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the code reported to the update service when the update fails with the given error.
+    fn error_code(error: &Self::Error) -> u32 {
+        let _ = error;
+        FailureReport::DEVICE_ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let mut manifest = Manifest::new(b"2");
+        manifest
+            .components
+            .push(Component::new(1, b"3", 16, &[0xAA; 4]))
+            .unwrap();
+
+        let mut buf = [0; MANIFEST_SIZE];
+        let encoded = manifest.encode(&mut buf).unwrap();
+        assert_eq!(
+            encoded,
+            &[
+                0xA2, 0x00, 0x41, b'2', 0x01, 0x81, 0xA4, 0x00, 0x01, 0x01, 0x41, b'3', 0x02, 0x10, 0x03, 0x44, 0xAA,
+                0xAA, 0xAA, 0xAA
+            ]
+        );
+
+        let decoded = Manifest::decode(encoded).unwrap();
+        assert_eq!(decoded.version, b"2");
+        assert_eq!(decoded.components.len(), 1);
+        assert_eq!(decoded.components[0].index, 1);
+        assert_eq!(decoded.components[0].version, b"3");
+        assert_eq!(decoded.components[0].size, 16);
+        assert_eq!(decoded.components[0].digest, [0xAA; 4]);
+
+        assert!(Manifest::decode(&encoded[..10]).is_none());
+    }
+}
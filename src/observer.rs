@@ -0,0 +1,93 @@
+/// Observer of the progress of a `FirmwareUpdater`, for driving progress bars, LEDs and similar.
+///
+/// All methods have a default implementation that does nothing, so implementors only need to
+/// handle the events they are interested in.
+pub trait UpdateObserver {
+    /// Writing of a new firmware version has started.
+    fn started(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// A block of firmware has been written to the device. The total size of the firmware is
+    /// provided if known.
+    fn written(&mut self, offset: u32, len: u32, total: Option<u32>) {
+        let _ = (offset, len, total);
+    }
+
+    /// The update service instructed the device to wait before polling again.
+    fn waiting(&mut self, delay_ms: u32) {
+        let _ = delay_ms;
+    }
+
+    /// The device firmware is in sync with the update service.
+    fn synced(&mut self) {}
+
+    /// The written firmware has been verified and the device is about to swap to it.
+    fn swapping(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// The written firmware has been verified and staged, and waits for the application to swap to it.
+    fn staged(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// The update service aborted the update, and the firmware being written has been discarded.
+    fn aborted(&mut self, reason: Option<u32>) {
+        let _ = reason;
+    }
+
+    /// A request to the update service failed and will be retried after a delay.
+    fn retrying(&mut self, failures: u32, delay_ms: u32) {
+        let _ = (failures, delay_ms);
+    }
+
+    /// The update failed and the updater is returning an error.
+    fn failed(&mut self) {}
+}
+
+/// An observer ignoring all events.
+pub struct NoObserver;
+
+impl UpdateObserver for NoObserver {}
+
+impl<O> UpdateObserver for &mut O
+where
+    O: UpdateObserver,
+{
+    fn started(&mut self, version: &[u8]) {
+        (**self).started(version)
+    }
+
+    fn written(&mut self, offset: u32, len: u32, total: Option<u32>) {
+        (**self).written(offset, len, total)
+    }
+
+    fn waiting(&mut self, delay_ms: u32) {
+        (**self).waiting(delay_ms)
+    }
+
+    fn synced(&mut self) {
+        (**self).synced()
+    }
+
+    fn swapping(&mut self, version: &[u8]) {
+        (**self).swapping(version)
+    }
+
+    fn staged(&mut self, version: &[u8]) {
+        (**self).staged(version)
+    }
+
+    fn aborted(&mut self, reason: Option<u32>) {
+        (**self).aborted(reason)
+    }
+
+    fn retrying(&mut self, failures: u32, delay_ms: u32) {
+        (**self).retrying(failures, delay_ms)
+    }
+
+    fn failed(&mut self) {
+        (**self).failed()
+    }
+}
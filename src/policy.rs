@@ -0,0 +1,214 @@
+use core::cmp::Ordering;
+
+/// Decides whether a device accepts being updated to a firmware version sent by the update service, for instance
+/// to protect against downgrades reintroducing vulnerabilities fixed in the running firmware.
+///
+/// The policy is consulted before the device is prepared for writing a new version, and before continuing a version
+/// left in progress by an earlier run. A rejected update is reported to the update service with
+/// `FailureReport::REJECTED`.
+pub trait UpdatePolicy {
+    /// Return true if the device may be updated from the `current` version to the `next` version.
+    fn accept(&mut self, current: &[u8], next: &[u8]) -> bool;
+}
+
+/// A policy accepting every version.
+pub struct AcceptAll;
+
+impl UpdatePolicy for AcceptAll {
+    fn accept(&mut self, _current: &[u8], _next: &[u8]) -> bool {
+        true
+    }
+}
+
+impl<P> UpdatePolicy for &mut P
+where
+    P: UpdatePolicy,
+{
+    fn accept(&mut self, current: &[u8], next: &[u8]) -> bool {
+        (**self).accept(current, next)
+    }
+}
+
+/// Both policies must accept the version.
+impl<A, B> UpdatePolicy for (A, B)
+where
+    A: UpdatePolicy,
+    B: UpdatePolicy,
+{
+    fn accept(&mut self, current: &[u8], next: &[u8]) -> bool {
+        self.0.accept(current, next) && self.1.accept(current, next)
+    }
+}
+
+/// A policy only accepting versions newer than the current version, comparing the versions as semantic versions.
+///
+/// Versions are parsed as `MAJOR.MINOR.PATCH`, optionally prefixed with `v`, and followed by a `-` pre-release and
+/// a `+` build metadata. Missing minor and patch numbers are zero. Pre-releases precede the release of the same
+/// version, and the build metadata is ignored. Versions that cannot be parsed are rejected.
+pub struct Semver;
+
+impl UpdatePolicy for Semver {
+    fn accept(&mut self, current: &[u8], next: &[u8]) -> bool {
+        match (Version::parse(current), Version::parse(next)) {
+            (Some(current), Some(next)) => next.compare(&current) == Ordering::Greater,
+            _ => false,
+        }
+    }
+}
+
+/// A policy rejecting versions with an anti-rollback counter lower than the counter of the device.
+///
+/// The counter of a version is extracted from the version bytes with the provided function, and versions without
+/// a counter are rejected. The counter of the device should be kept in storage that can only be incremented,
+/// such as OTP memory or the monotonic counters of a secure element, and be raised once a new firmware has been
+/// marked as booted.
+pub struct AntiRollback<E>
+where
+    E: FnMut(&[u8]) -> Option<u32>,
+{
+    counter: u32,
+    extract: E,
+}
+
+impl<E> AntiRollback<E>
+where
+    E: FnMut(&[u8]) -> Option<u32>,
+{
+    /// Create a policy for a device with the given anti-rollback counter.
+    pub fn new(counter: u32, extract: E) -> Self {
+        Self { counter, extract }
+    }
+}
+
+impl<E> UpdatePolicy for AntiRollback<E>
+where
+    E: FnMut(&[u8]) -> Option<u32>,
+{
+    fn accept(&mut self, _current: &[u8], next: &[u8]) -> bool {
+        (self.extract)(next).is_some_and(|counter| counter >= self.counter)
+    }
+}
+
+/// A policy only accepting the listed versions.
+pub struct AllowList<'a> {
+    versions: &'a [&'a [u8]],
+}
+
+impl<'a> AllowList<'a> {
+    /// Create a policy accepting the given versions.
+    pub fn new(versions: &'a [&'a [u8]]) -> Self {
+        Self { versions }
+    }
+}
+
+impl<'a> UpdatePolicy for AllowList<'a> {
+    fn accept(&mut self, _current: &[u8], next: &[u8]) -> bool {
+        self.versions.contains(&next)
+    }
+}
+
+/// A semantic version, without its build metadata.
+struct Version<'a> {
+    core: [u64; 3],
+    pre: Option<&'a str>,
+}
+
+impl<'a> Version<'a> {
+    fn parse(version: &'a [u8]) -> Option<Self> {
+        let version = core::str::from_utf8(version).ok()?;
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let version = version.split('+').next()?;
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, pre)) => (numbers, Some(pre)),
+            None => (version, None),
+        };
+        if pre.is_some_and(|pre| pre.split('.').any(str::is_empty)) {
+            return None;
+        }
+        let mut core = [0; 3];
+        for (i, number) in numbers.split('.').enumerate() {
+            *core.get_mut(i)? = number_of(number)?;
+        }
+        Some(Self { core, pre })
+    }
+
+    /// Compare by semantic version precedence.
+    fn compare(&self, other: &Self) -> Ordering {
+        self.core.cmp(&other.core).then_with(|| match (self.pre, other.pre) {
+            (None, None) => Ordering::Equal,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => {
+                let (mut a, mut b) = (a.split('.'), b.split('.'));
+                loop {
+                    let ordering = match (a.next(), b.next()) {
+                        (None, None) => return Ordering::Equal,
+                        (None, Some(_)) => return Ordering::Less,
+                        (Some(_), None) => return Ordering::Greater,
+                        (Some(a), Some(b)) => match (number_of(a), number_of(b)) {
+                            (Some(a), Some(b)) => a.cmp(&b),
+                            (Some(_), None) => Ordering::Less,
+                            (None, Some(_)) => Ordering::Greater,
+                            (None, None) => a.cmp(b),
+                        },
+                    };
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Parse a numeric identifier of a semantic version.
+fn number_of(identifier: &str) -> Option<u64> {
+    if identifier.is_empty() || !identifier.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    identifier.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_semver() {
+        let mut policy = Semver;
+        assert!(policy.accept(b"1.2.3", b"1.2.4"));
+        assert!(policy.accept(b"1.2.3", b"v1.10.0"));
+        assert!(policy.accept(b"1", b"2"));
+        assert!(policy.accept(b"1.0.0-rc.1", b"1.0.0"));
+        assert!(policy.accept(b"1.0.0-alpha", b"1.0.0-alpha.1"));
+        assert!(policy.accept(b"1.0.0-alpha.2", b"1.0.0-alpha.10"));
+        assert!(policy.accept(b"1.0.0-2", b"1.0.0-beta"));
+        assert!(policy.accept(b"1.0.0", b"1.0.1-rc.1"));
+
+        assert!(!policy.accept(b"1.2.4", b"1.2.3"));
+        assert!(!policy.accept(b"1.2.3", b"1.2.3+build.5"));
+        assert!(!policy.accept(b"1.0.0", b"1.0.0-rc.1"));
+        assert!(!policy.accept(b"1.2.3", b"1.2.3.4"));
+        assert!(!policy.accept(b"1.2.3", b"1.x"));
+        assert!(!policy.accept(b"1.2.3", b"2.0.0-"));
+        assert!(!policy.accept(b"garbage", b"1.0.0"));
+    }
+
+    #[test]
+    fn test_anti_rollback() {
+        // The counter is the first byte of the version
+        let mut policy = AntiRollback::new(3, |version: &[u8]| version.first().map(|c| *c as u32));
+        assert!(policy.accept(&[3, 1], &[3, 2]));
+        assert!(policy.accept(&[3, 1], &[4, 0]));
+        assert!(!policy.accept(&[3, 1], &[2, 9]));
+        assert!(!policy.accept(&[3, 1], &[]));
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let mut policy = (Semver, AllowList::new(&[b"1.1.0", b"1.3.0"]));
+        assert!(policy.accept(b"1.0.0", b"1.1.0"));
+        assert!(!policy.accept(b"1.0.0", b"1.2.0"));
+        assert!(!policy.accept(b"1.2.0", b"1.1.0"));
+    }
+}
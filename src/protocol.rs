@@ -4,11 +4,12 @@ use {
         hash::{Hash, Hasher},
         ops::Deref,
     },
+    postcard::take_from_bytes,
     serde::{de::Visitor, Deserialize, Serialize},
 };
 
 /// Represents the current state of firmware and firmware being written on a device.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Status<'a> {
     /// The current version of the firmware.
@@ -20,10 +21,221 @@ pub struct Status<'a> {
     pub correlation_id: Option<u32>,
     /// The status of the firmware being written to a device.
     pub update: Option<UpdateStatus<'a>>,
+    /// The protocol revision and capabilities supported by the device. Not sent by devices predating
+    /// protocol negotiation, which only support the commands of revision 0.
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+    /// The reason the device gave up on the last update, sent once before the updater returns an error.
+    #[serde(borrow, default)]
+    pub failure: Option<FailureReport<'a>>,
+    /// Whether the current firmware has been confirmed, is running in a trial boot, or was rolled back to after
+    /// a failed boot of a new firmware.
+    #[serde(default)]
+    pub boot: Option<BootState>,
+    /// Whether the firmware being written has been verified and staged, and is waiting for the application to
+    /// swap to it.
+    #[serde(default)]
+    pub staged: bool,
+}
+
+/// The boot state of the firmware running on a device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BootState {
+    /// The running firmware has been marked as good.
+    Confirmed,
+    /// The device is running a new firmware for the first time. The bootloader reverts to the previous firmware
+    /// on the next reset, unless the firmware is marked as good.
+    Trial,
+    /// The new firmware failed to boot or was never marked as good, and the bootloader reverted to the previous
+    /// firmware.
+    RolledBack,
+}
+
+/// The protocol revision and capabilities supported by a device.
+///
+/// The update service must only send commands supported by the device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Protocol {
+    /// The protocol revision implemented by the device.
+    pub revision: u16,
+    /// The optional protocol features supported by the device.
+    pub capabilities: Capabilities,
+}
+
+impl Protocol {
+    /// The protocol revision implemented by this crate.
+    ///
+    /// * Revision 0: `Wait`, `Sync`, `Write` and `Swap` commands.
+    /// * Revision 1: The `protocol` field in `Status`, and the `SignedSwap` and `Start` commands.
+    /// * Revision 2: The `failure` field in `Status`.
+    /// * Revision 3: The `Abort` command.
+    /// * Revision 4: The `boot` field in `Status`.
+    /// * Revision 5: The `Patch` command.
+    /// * Revision 6: The `WriteCompressed` command.
+    /// * Revision 7: The `staged` field in `Status`.
+    pub const REVISION: u16 = 7;
+
+    /// The protocol revision implemented by this crate, and the capabilities of every updater.
+    pub const fn current() -> Self {
+        Self {
+            revision: Self::REVISION,
+            capabilities: Capabilities::DEFAULT,
+        }
+    }
+
+    /// Returns true if the revision is known to this crate, and the capabilities only contain features defined by
+    /// the revision.
+    pub const fn is_known(&self) -> bool {
+        let defined = match self.revision {
+            1 | 2 => Capabilities::SIGNED_SWAP.union(Capabilities::START),
+            3 | 4 => Capabilities::SIGNED_SWAP
+                .union(Capabilities::START)
+                .union(Capabilities::ABORT),
+            5 => Capabilities::ALL.difference(Capabilities::HEATSHRINK),
+            6..=Self::REVISION => Capabilities::ALL,
+            _ => return false,
+        };
+        defined.contains(self.capabilities)
+    }
+}
+
+/// A set of optional protocol features.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// No optional features.
+    pub const NONE: Self = Self(0);
+    /// The device supports the `SignedSwap` command.
+    pub const SIGNED_SWAP: Self = Self(1 << 0);
+    /// The device supports the `Start` command.
+    pub const START: Self = Self(1 << 1);
+    /// The device supports the `Abort` command.
+    pub const ABORT: Self = Self(1 << 2);
+    /// The device supports the `Patch` command, and is able to apply a patch against its current firmware.
+    pub const DELTA: Self = Self(1 << 3);
+    /// The device accepts `WriteCompressed` commands with the `Codec::Heatshrink` codec.
+    pub const HEATSHRINK: Self = Self(1 << 4);
+    /// The features implemented by every updater, advertised unless other capabilities are given. Patches and
+    /// codecs depend on the device, and are only advertised when configured.
+    pub const DEFAULT: Self = Self(Self::SIGNED_SWAP.0 | Self::START.0 | Self::ABORT.0);
+    /// All features supported by this crate.
+    pub const ALL: Self =
+        Self(Self::SIGNED_SWAP.0 | Self::START.0 | Self::ABORT.0 | Self::DELTA.0 | Self::HEATSHRINK.0);
+
+    /// Create a set of capabilities from its bits.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Return the bits of the set of capabilities.
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if all the given capabilities are contained in this set.
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return the union of this set and the given capabilities.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Return this set without the given capabilities.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// A codec used for compressing the firmware sent in `WriteCompressed` commands.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Codec {
+    /// heatshrink, with a window of 2^8 bytes and a lookahead of 2^4 bytes.
+    Heatshrink,
+}
+
+impl Codec {
+    /// Return the capability advertised by devices accepting this codec.
+    pub const fn capability(&self) -> Capabilities {
+        match self {
+            Self::Heatshrink => Capabilities::HEATSHRINK,
+        }
+    }
+}
+
+/// A report of a failed update, sent by a device before giving up on the update.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FailureReport<'a> {
+    /// The stage of the update that failed.
+    pub stage: FailureStage,
+    /// The reason for the failure. Either one of the codes defined here, or a device specific code.
+    pub code: u32,
+    /// The version of the firmware that failed to be written.
+    #[serde(borrow)]
+    pub version: Bytes<'a>,
+}
+
+impl<'a> FailureReport<'a> {
+    /// The firmware device failed, without a more specific reason.
+    pub const DEVICE_ERROR: u32 = 1;
+    /// The version of the firmware could not be decoded by the device.
+    pub const DECODE_VERSION: u32 = 2;
+    /// A command was for a different version than the firmware being written.
+    pub const VERSION_MISMATCH: u32 = 3;
+    /// The update service kept sending blocks at an unexpected offset.
+    pub const OFFSET_MISMATCH: u32 = 4;
+    /// The firmware does not fit in the device.
+    pub const IMAGE_TOO_LARGE: u32 = 5;
+    /// The checksum of the written firmware does not match the checksum sent by the update service.
+    pub const CHECKSUM_MISMATCH: u32 = 6;
+    /// The firmware signature is missing or invalid.
+    pub const INVALID_SIGNATURE: u32 = 7;
+    /// A patch could not be applied to the current firmware.
+    pub const INVALID_PATCH: u32 = 8;
+    /// Compressed blocks were sent with a codec not accepted by the device.
+    pub const UNSUPPORTED_CODEC: u32 = 9;
+    /// The authentication tag of an encrypted firmware is missing or does not verify.
+    pub const AUTHENTICATION_FAILED: u32 = 10;
+    /// The manifest of a multi-component firmware is invalid or does not match the images of the components.
+    pub const INVALID_MANIFEST: u32 = 11;
+    /// The version of the firmware was rejected by the update policy of the device.
+    pub const REJECTED: u32 = 12;
+    /// The first of the codes reserved for device specific errors.
+    pub const DEVICE_SPECIFIC: u32 = 0x1000;
+
+    /// Create a new failure report.
+    pub fn new(stage: FailureStage, code: u32, version: &'a [u8]) -> Self {
+        Self {
+            stage,
+            code,
+            version: Bytes::new(version),
+        }
+    }
+}
+
+/// The stage of an update.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FailureStage {
+    /// Preparing the device for writing a new firmware version.
+    Start,
+    /// Writing a block of firmware.
+    Write,
+    /// Swapping to the new firmware.
+    Swap,
+    /// Verifying the checksum and signature of the written firmware.
+    Verify,
 }
 
 /// The status of the firmware being written to a device.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UpdateStatus<'a> {
     /// The version of the firmware being written to the device.
@@ -41,6 +253,10 @@ impl<'a> Status<'a> {
             mtu,
             correlation_id,
             update: None,
+            protocol: Some(Protocol::current()),
+            failure: None,
+            boot: None,
+            staged: false,
         }
     }
 
@@ -60,8 +276,120 @@ impl<'a> Status<'a> {
                 offset,
                 version: Bytes::new(next_version),
             }),
+            protocol: Some(Protocol::current()),
+            failure: None,
+            boot: None,
+            staged: false,
+        }
+    }
+
+    /// Create a status update reporting that the device gave up on updating the firmware.
+    pub fn failed(
+        version: &'a [u8],
+        mtu: Option<u32>,
+        failure: FailureReport<'a>,
+        correlation_id: Option<u32>,
+    ) -> Self {
+        Self {
+            version: Bytes::new(version),
+            mtu,
+            correlation_id,
+            update: None,
+            protocol: Some(Protocol::current()),
+            failure: Some(failure),
+            boot: None,
+            staged: false,
+        }
+    }
+
+    /// Add the boot state of the current firmware to the status update.
+    pub fn with_boot(self, boot: BootState) -> Self {
+        Self {
+            boot: Some(boot),
+            ..self
+        }
+    }
+
+    /// Report the firmware being written as verified and staged, waiting for the application to swap to it.
+    pub fn with_staged(self) -> Self {
+        Self { staged: true, ..self }
+    }
+
+    /// Advertise the given capabilities in the status update, instead of `Capabilities::DEFAULT`.
+    pub fn with_capabilities(self, capabilities: Capabilities) -> Self {
+        Self {
+            protocol: Some(Protocol {
+                revision: Protocol::REVISION,
+                capabilities,
+            }),
+            ..self
         }
     }
+
+    /// Returns true if the device supports the given capabilities.
+    pub fn supports(&self, capabilities: Capabilities) -> bool {
+        self.protocol
+            .map(|p| p.capabilities.contains(capabilities))
+            .unwrap_or(false)
+    }
+
+    /// Decode a status received in a frame of the serial protocol.
+    ///
+    /// Devices of revision 0 send their status followed by the leftover bytes of an earlier frame rather than
+    /// zeros. The fields added by later revisions are therefore only decoded if the status advertises a known
+    /// revision, see `Protocol::is_known`, and the fields of that revision decode. Otherwise the status is decoded
+    /// as sent by a device of revision 0.
+    pub fn from_frame(frame: &'a [u8]) -> Result<Self, postcard::Error> {
+        let (head, rest) = take_from_bytes::<Revision0>(frame)?;
+        let mut status = Self {
+            version: head.version,
+            mtu: head.mtu,
+            correlation_id: head.correlation_id,
+            update: head.update,
+            protocol: None,
+            failure: None,
+            boot: None,
+            staged: false,
+        };
+        if let Some((protocol, failure, boot, staged)) = decode_revision(rest) {
+            status.protocol = Some(protocol);
+            status.failure = failure;
+            status.boot = boot;
+            status.staged = staged;
+        }
+        Ok(status)
+    }
+}
+
+/// The fields of a status sent by devices of revision 0.
+#[derive(Deserialize)]
+struct Revision0<'a> {
+    #[serde(borrow)]
+    version: Bytes<'a>,
+    mtu: Option<u32>,
+    correlation_id: Option<u32>,
+    update: Option<UpdateStatus<'a>>,
+}
+
+/// Decode the fields of a status following the fields of revision 0, up to the fields of the revision advertised
+/// in the status. Returns `None` if the revision is unknown or its fields do not decode.
+#[allow(clippy::type_complexity)]
+fn decode_revision(data: &[u8]) -> Option<(Protocol, Option<FailureReport<'_>>, Option<BootState>, bool)> {
+    let (protocol, data) = take_from_bytes::<Option<Protocol>>(data).ok()?;
+    let protocol = protocol.filter(Protocol::is_known)?;
+    let (failure, data) = match protocol.revision {
+        2.. => take_from_bytes(data).ok()?,
+        _ => (None, data),
+    };
+    let (boot, data) = match protocol.revision {
+        4.. => take_from_bytes(data).ok()?,
+        _ => (None, data),
+    };
+    let (staged, _) = match protocol.revision {
+        7.. => take_from_bytes(data).ok()?,
+        _ => (false, data),
+    };
+    Some((protocol, failure, boot, staged))
 }
 
 /// Represents a command issued from the update service to a device.
@@ -109,9 +437,101 @@ pub enum Command<'a> {
         #[serde(borrow)]
         checksum: Bytes<'a>,
     },
+    /// Same as `Swap`, but carrying a signature of the firmware checksum that the device should verify before swapping.
+    SignedSwap {
+        /// The version that was used for deciding the device is ready to swap. The device should check it matches the version being written.
+        #[serde(borrow)]
+        version: Bytes<'a>,
+        /// Correlation id matching the id sent in the status update.
+        correlation_id: Option<u32>,
+        /// The full checksum of the firmware being written. The device should compare this with the checksum of the firmware it has written before swapping.
+        #[serde(borrow)]
+        checksum: Bytes<'a>,
+        /// The signature of the checksum, created with the release key of the firmware.
+        #[serde(borrow)]
+        signature: Bytes<'a>,
+    },
+    /// Tell the device to prepare for writing a new firmware version, before the first block is sent.
+    Start {
+        /// The firmware version that will be written.
+        #[serde(borrow)]
+        version: Bytes<'a>,
+        /// Correlation id matching the id sent in the status update.
+        correlation_id: Option<u32>,
+        /// The total size of the firmware in bytes.
+        size: u32,
+    },
+    /// Tell the device to discard the firmware being written, withdrawing the update.
+    Abort {
+        /// Correlation id matching the id sent in the status update.
+        correlation_id: Option<u32>,
+        /// The reason for aborting the update, as defined by the update service.
+        reason: Option<u32>,
+    },
+    /// A block of a patch against the current firmware, which the device should apply to produce the firmware
+    /// being written. Only sent to devices supporting `Capabilities::DELTA`.
+    Patch {
+        /// The firmware version produced by the patch. The device should check that this matches version it has been writing so far.
+        #[serde(borrow)]
+        version: Bytes<'a>,
+        /// Correlation id matching the id sent in the status update.
+        correlation_id: Option<u32>,
+        /// The offset of this block in the patch.
+        offset: u32,
+        /// The patch data to apply.
+        #[serde(borrow)]
+        data: Bytes<'a>,
+    },
+    /// Same as `Write`, but carrying a block of the compressed firmware. Only sent to devices accepting the codec.
+    WriteCompressed {
+        /// The firmware version that this block corresponds to. The device should check that this matches version it has been writing so far.
+        #[serde(borrow)]
+        version: Bytes<'a>,
+        /// Correlation id matching the id sent in the status update.
+        correlation_id: Option<u32>,
+        /// The offset of this block in the compressed firmware.
+        offset: u32,
+        /// The codec used for compressing the firmware.
+        codec: Codec,
+        /// The compressed firmware data to write.
+        #[serde(borrow)]
+        data: Bytes<'a>,
+    },
 }
 
 impl<'a> Command<'a> {
+    /// Return the correlation id of the command.
+    pub fn correlation_id(&self) -> Option<u32> {
+        match self {
+            Self::Wait { correlation_id, .. }
+            | Self::Sync { correlation_id, .. }
+            | Self::Write { correlation_id, .. }
+            | Self::Swap { correlation_id, .. }
+            | Self::SignedSwap { correlation_id, .. }
+            | Self::Start { correlation_id, .. }
+            | Self::Abort { correlation_id, .. }
+            | Self::Patch { correlation_id, .. }
+            | Self::WriteCompressed { correlation_id, .. } => *correlation_id,
+        }
+    }
+
+    /// Return the command with the given correlation id, for instance to restore the correlation id of a request
+    /// forwarded with a different id.
+    pub fn with_correlation_id(mut self, id: Option<u32>) -> Self {
+        match &mut self {
+            Self::Wait { correlation_id, .. }
+            | Self::Sync { correlation_id, .. }
+            | Self::Write { correlation_id, .. }
+            | Self::Swap { correlation_id, .. }
+            | Self::SignedSwap { correlation_id, .. }
+            | Self::Start { correlation_id, .. }
+            | Self::Abort { correlation_id, .. }
+            | Self::Patch { correlation_id, .. }
+            | Self::WriteCompressed { correlation_id, .. } => *correlation_id = id,
+        }
+        self
+    }
+
     /// Create a new Wait command
     pub fn new_wait(poll: Option<u32>, correlation_id: Option<u32>) -> Self {
         Self::Wait { correlation_id, poll }
@@ -135,6 +555,35 @@ impl<'a> Command<'a> {
         }
     }
 
+    /// Create a new SignedSwap command
+    pub fn new_signed_swap(
+        version: &'a [u8],
+        checksum: &'a [u8],
+        signature: &'a [u8],
+        correlation_id: Option<u32>,
+    ) -> Self {
+        Self::SignedSwap {
+            version: Bytes::new(version),
+            correlation_id,
+            checksum: Bytes::new(checksum),
+            signature: Bytes::new(signature),
+        }
+    }
+
+    /// Create a new Start command.
+    pub fn new_start(version: &'a [u8], size: u32, correlation_id: Option<u32>) -> Self {
+        Self::Start {
+            version: Bytes::new(version),
+            correlation_id,
+            size,
+        }
+    }
+
+    /// Create a new Abort command.
+    pub fn new_abort(reason: Option<u32>, correlation_id: Option<u32>) -> Self {
+        Self::Abort { correlation_id, reason }
+    }
+
     /// Create a new Write command.
     pub fn new_write(version: &'a [u8], offset: u32, data: &'a [u8], correlation_id: Option<u32>) -> Self {
         Self::Write {
@@ -144,19 +593,51 @@ impl<'a> Command<'a> {
             data: Bytes::new(data),
         }
     }
+
+    /// Create a new WriteCompressed command.
+    pub fn new_write_compressed(
+        version: &'a [u8],
+        codec: Codec,
+        offset: u32,
+        data: &'a [u8],
+        correlation_id: Option<u32>,
+    ) -> Self {
+        Self::WriteCompressed {
+            version: Bytes::new(version),
+            correlation_id,
+            offset,
+            codec,
+            data: Bytes::new(data),
+        }
+    }
+
+    /// Create a new Patch command.
+    pub fn new_patch(version: &'a [u8], offset: u32, data: &'a [u8], correlation_id: Option<u32>) -> Self {
+        Self::Patch {
+            version: Bytes::new(version),
+            correlation_id,
+            offset,
+            data: Bytes::new(data),
+        }
+    }
 }
 
 /// Represents a serde serializeable byte slice.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Bytes<'a> {
     data: &'a [u8],
 }
 
 impl<'a> Bytes<'a> {
-    fn new(data: &'a [u8]) -> Self {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
         Self { data }
     }
+
+    /// Return the bytes, borrowed for as long as the data they were decoded from.
+    pub(crate) fn data(&self) -> &'a [u8] {
+        self.data
+    }
 }
 
 impl<'a> Serialize for Bytes<'a> {
@@ -269,7 +750,16 @@ mod tests {
 
         let s = Status::update(version, mtu, offset, next_version, cid);
         let update = encode(&s);
-        println!("Serialized size:\n FIRST:\t{}\nUPDATE:\t{}", first.len(), update.len(),);
+
+        let failure = FailureReport::new(FailureStage::Write, FailureReport::DEVICE_ERROR, next_version);
+        let s = Status::failed(version, mtu, failure, cid);
+        let failed = encode(&s);
+        println!(
+            "Serialized size:\n FIRST:\t{}\nUPDATE:\t{}\nFAILED:\t{}",
+            first.len(),
+            update.len(),
+            failed.len()
+        );
     }
 
     #[test]
@@ -282,6 +772,9 @@ mod tests {
         let s = Command::new_write(version, 0, payload, None);
         let write = encode(&s);
 
+        let s = Command::new_start(version, 1024, None);
+        let start = encode(&s);
+
         let s = Command::new_wait(Some(1), None);
         let wait = encode(&s);
 
@@ -290,12 +783,21 @@ mod tests {
 
         let s = Command::new_swap(version, checksum, None);
         let swap = encode(&s);
+
+        let s = Command::new_signed_swap(version, checksum, &[0; 64], None);
+        let signed_swap = encode(&s);
+
+        let s = Command::new_abort(Some(1), None);
+        let abort = encode(&s);
         println!(
-            "Serialized size:\n WRITE:\t{}\nWAIT:\t{}\nSYNC:\t{}\nSWAP:\t{}",
+            "Serialized size:\n START:\t{}\nWRITE:\t{}\nWAIT:\t{}\nSYNC:\t{}\nSWAP:\t{}\nSIGNED SWAP:\t{}\nABORT:\t{}",
+            start.len(),
             write.len(),
             wait.len(),
             sync.len(),
-            swap.len()
+            swap.len(),
+            signed_swap.len(),
+            abort.len()
         );
     }
 
@@ -0,0 +1,188 @@
+use {
+    crate::protocol::Bytes,
+    core::convert::Infallible,
+    heapless::Vec,
+    serde::{Deserialize, Serialize},
+};
+
+/// The maximum size in bytes of the records stored in a `ResumeStore`.
+pub const RESUME_RECORD_SIZE: usize = 256;
+
+/// Persistent storage for the progress of a firmware update, so that an update interrupted by a reset
+/// can be resumed instead of starting over.
+///
+/// The updater stores a record of at most `RESUME_RECORD_SIZE` bytes every `checkpoint_interval` blocks,
+/// and clears it when a new update is started, or the update is finished or aborted. The store can for
+/// instance be backed by a page of flash or, with the `std` feature, a file.
+#[allow(async_fn_in_trait)]
+pub trait ResumeStore {
+    /// Error type
+    type Error: core::fmt::Debug;
+
+    /// Read the stored record into `buf`, returning its size, or `None` if no record is stored.
+    async fn load(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+
+    /// Replace the stored record.
+    async fn store(&mut self, record: &[u8]) -> Result<(), Self::Error>;
+
+    /// Remove the stored record.
+    async fn clear(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A store that does not keep any records, so that interrupted updates are never resumed.
+pub struct NoStore;
+
+impl ResumeStore for NoStore {
+    type Error = Infallible;
+
+    async fn load(&mut self, _buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn store(&mut self, _record: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A store keeping the record in memory, useful in tests and for devices with retained RAM.
+pub struct MemoryStore {
+    record: Option<Vec<u8, RESUME_RECORD_SIZE>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self { record: None }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResumeStore for MemoryStore {
+    type Error = ();
+
+    async fn load(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        match &self.record {
+            Some(record) => {
+                buf.get_mut(..record.len()).ok_or(())?.copy_from_slice(record);
+                Ok(Some(record.len()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn store(&mut self, record: &[u8]) -> Result<(), Self::Error> {
+        self.record.replace(Vec::from_slice(record)?);
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<(), Self::Error> {
+        self.record.take();
+        Ok(())
+    }
+}
+
+impl<S> ResumeStore for &mut S
+where
+    S: ResumeStore,
+{
+    type Error = S::Error;
+
+    async fn load(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        (**self).load(buf).await
+    }
+
+    async fn store(&mut self, record: &[u8]) -> Result<(), Self::Error> {
+        (**self).store(record).await
+    }
+
+    async fn clear(&mut self) -> Result<(), Self::Error> {
+        (**self).clear().await
+    }
+}
+
+#[cfg(feature = "std")]
+pub use file::FileStore;
+
+#[cfg(feature = "std")]
+mod file {
+    extern crate std;
+    use std::{fs, io, path::PathBuf};
+
+    /// A store keeping the record in a file.
+    pub struct FileStore {
+        path: PathBuf,
+    }
+
+    impl FileStore {
+        /// Create a store keeping the record in the file at `path`.
+        pub fn new(path: impl Into<PathBuf>) -> Self {
+            Self { path: path.into() }
+        }
+    }
+
+    impl super::ResumeStore for FileStore {
+        type Error = io::Error;
+
+        async fn load(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+            let record = match fs::read(&self.path) {
+                Ok(record) => record,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            buf.get_mut(..record.len())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?
+                .copy_from_slice(&record);
+            Ok(Some(record.len()))
+        }
+
+        async fn store(&mut self, record: &[u8]) -> Result<(), Self::Error> {
+            // Replace the record atomically, so that a reset while writing does not corrupt it
+            let tmp = self.path.with_extension("tmp");
+            fs::write(&tmp, record)?;
+            fs::rename(&tmp, &self.path)
+        }
+
+        async fn clear(&mut self) -> Result<(), Self::Error> {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// The progress of a firmware update, as stored in a `ResumeStore`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Checkpoint<'a> {
+    /// The version of the firmware being written.
+    #[serde(borrow)]
+    pub(crate) version: Bytes<'a>,
+    /// The offset of the next block to write.
+    pub(crate) offset: u32,
+    /// The total size of the firmware, if known.
+    pub(crate) total: Option<u32>,
+    /// The saved digest of the blocks written so far.
+    #[serde(borrow)]
+    pub(crate) checksum: Bytes<'a>,
+}
+
+impl<'a> Checkpoint<'a> {
+    /// Encode the checkpoint into `buf`, returning the encoded record.
+    pub(crate) fn encode<'b>(&self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        postcard::to_slice(self, buf).ok().map(|record| &*record)
+    }
+
+    /// Decode a checkpoint from a stored record.
+    pub(crate) fn decode(record: &'a [u8]) -> Option<Self> {
+        postcard::from_bytes(record).ok()
+    }
+}
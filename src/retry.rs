@@ -0,0 +1,138 @@
+/// Policy for delaying requests to the update service after a failure or timeout.
+///
+/// The delay starts at `initial_delay_ms` and is multiplied by `multiplier` for every consecutive
+/// failure, up to `max_delay_ms`. A random delay of up to `jitter_ms` is added to every retry, see
+/// `FirmwareUpdater::with_seed`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    /// Delay after the first failure in milliseconds.
+    pub initial_delay_ms: u32,
+    /// Factor the delay is multiplied with for every consecutive failure.
+    pub multiplier: u32,
+    /// Upper bound of the delay in milliseconds, not including jitter.
+    pub max_delay_ms: u32,
+    /// Upper bound of the random delay added to every retry in milliseconds.
+    ///
+    /// The jitter of devices seeded the same way follows the same sequence. Seed the updater with a device
+    /// specific value using `with_seed`, or with a random number generator using `with_rng`.
+    pub jitter_ms: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1_000,
+            multiplier: 2,
+            max_delay_ms: 60_000,
+            jitter_ms: 1_000,
+        }
+    }
+}
+
+/// The backoff state of consecutive failed requests.
+pub(crate) struct Backoff {
+    delay_ms: Option<u32>,
+    rng: u32,
+}
+
+impl Backoff {
+    /// The seed used instead of zero, which xorshift cannot start from.
+    const DEFAULT_SEED: u32 = 0x9e37_79b9;
+
+    /// Create a backoff using the seed for generating jitter.
+    pub(crate) fn new(seed: u32) -> Self {
+        Self {
+            delay_ms: None,
+            rng: if seed == 0 { Self::DEFAULT_SEED } else { seed },
+        }
+    }
+
+    /// Return the delay in milliseconds to wait before retrying a failed request.
+    pub(crate) fn next(&mut self, policy: &RetryPolicy) -> u32 {
+        let delay_ms = match self.delay_ms {
+            None => policy.initial_delay_ms,
+            Some(delay_ms) => delay_ms.saturating_mul(policy.multiplier),
+        }
+        .min(policy.max_delay_ms);
+        self.delay_ms.replace(delay_ms);
+        delay_ms.saturating_add(self.jitter(policy.jitter_ms))
+    }
+
+    /// Reset the delay after a successful request.
+    pub(crate) fn reset(&mut self) {
+        self.delay_ms = None;
+    }
+
+    fn jitter(&mut self, max_ms: u32) -> u32 {
+        if max_ms == 0 {
+            return 0;
+        }
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng % max_ms.saturating_add(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 3,
+            max_delay_ms: 1_000,
+            jitter_ms: 0,
+        };
+        let mut backoff = Backoff::new(0);
+        assert_eq!(backoff.next(&policy), 100);
+        assert_eq!(backoff.next(&policy), 300);
+        assert_eq!(backoff.next(&policy), 900);
+        assert_eq!(backoff.next(&policy), 1_000);
+        assert_eq!(backoff.next(&policy), 1_000);
+
+        backoff.reset();
+        assert_eq!(backoff.next(&policy), 100);
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 1,
+            max_delay_ms: 100,
+            jitter_ms: 50,
+        };
+
+        // Jitter is applied without a seed
+        let mut unseeded = Backoff::new(0);
+        let delays: [u32; 8] = core::array::from_fn(|_| unseeded.next(&policy));
+        assert!(delays.iter().all(|d| (100..=150).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn jitter_seeds() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 2,
+            max_delay_ms: 400,
+            jitter_ms: 50,
+        };
+
+        let mut first = Backoff::new(1);
+        let mut second = Backoff::new(2);
+        let first: [u32; 8] = core::array::from_fn(|_| first.next(&policy));
+        let second: [u32; 8] = core::array::from_fn(|_| second.next(&policy));
+        for (i, (a, b)) in first.iter().zip(&second).enumerate() {
+            let base = core::cmp::min(100 << i, 400);
+            assert!((base..=base + 50).contains(a));
+            assert!((base..=base + 50).contains(b));
+        }
+        assert_ne!(first, second);
+    }
+}
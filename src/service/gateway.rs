@@ -0,0 +1,366 @@
+use {
+    super::FRAME_SIZE,
+    crate::{
+        protocol::{Command, Status},
+        traits::UpdateService,
+    },
+    core::{
+        cell::{Cell, RefCell},
+        future::{poll_fn, Future},
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    },
+    postcard::{from_bytes, to_slice},
+};
+
+/// A gateway multiplexing the updates of several devices over a single update service.
+///
+/// Every device is updated by its own `FirmwareUpdater`, using the `GatewayService` returned by `service` for the
+/// device. The status updates of the devices are sent to the update service with correlation ids unique across
+/// the devices, and every command received is routed back to the device whose status it responds to, even if the
+/// update service responds to the requests out of order. The updaters can then run concurrently, for instance
+/// with `futures::future::join`.
+pub struct Gateway<T, const N: usize>
+where
+    T: UpdateService,
+{
+    /// The update service, taken by the device sending a request until the response is received.
+    service: RefCell<Option<T>>,
+    slots: RefCell<[Slot; N]>,
+    correlation_id: Cell<u32>,
+}
+
+/// The request in progress for a device.
+struct Slot {
+    /// The correlation id of the status sent to the update service, and the correlation id set by the device.
+    requested: Option<(u32, Option<u32>)>,
+    /// Whether a command for the request has been received in `frame`.
+    received: bool,
+    /// The encoded command received for the request.
+    frame: [u8; FRAME_SIZE],
+    /// The waker of the device waiting for the command or for the update service.
+    waker: Option<Waker>,
+}
+
+impl<T, const N: usize> Gateway<T, N>
+where
+    T: UpdateService,
+{
+    /// Create a gateway for `N` devices, multiplexed over the provided update service.
+    pub fn new(service: T) -> Self {
+        Self {
+            service: RefCell::new(Some(service)),
+            slots: RefCell::new(core::array::from_fn(|_| Slot {
+                requested: None,
+                received: false,
+                frame: [0; FRAME_SIZE],
+                waker: None,
+            })),
+            correlation_id: Cell::new(0),
+        }
+    }
+
+    /// Return the update service for the device at `index`, which must be less than `N`.
+    pub fn service(&self, index: usize) -> GatewayService<'_, T, N> {
+        assert!(index < N, "Device index out of range");
+        GatewayService {
+            gateway: self,
+            index,
+            buf: [0; FRAME_SIZE],
+        }
+    }
+
+    /// Return the underlying update service.
+    pub fn into_inner(self) -> T {
+        // The service is always returned when a request completes or is dropped
+        self.service.into_inner().unwrap()
+    }
+
+    /// Take the update service for sending a request, unless another device is sending one.
+    fn lease(&self) -> Option<Lease<'_, T, N>> {
+        let service = self.service.borrow_mut().take()?;
+        Some(Lease {
+            gateway: self,
+            service: Some(service),
+        })
+    }
+
+    /// Wait until a command is received for the device at `index`, or the update service is released.
+    async fn wait(&self, index: usize) {
+        poll_fn(|cx| {
+            let mut slots = self.slots.borrow_mut();
+            let slot = &mut slots[index];
+            if slot.received || self.service.borrow().is_some() {
+                Poll::Ready(())
+            } else {
+                slot.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Return the correlation id to use for the next status sent to the update service.
+    fn next_correlation_id(&self) -> u32 {
+        let id = self.correlation_id.get().wrapping_add(1);
+        self.correlation_id.set(id);
+        id
+    }
+
+    /// Deliver a command to the device whose request it responds to. Commands without a correlation id are
+    /// delivered to the device at `index`, which sent the request.
+    fn route(&self, index: usize, command: Command<'_>) -> Result<(), GatewayError<T::Error>> {
+        let mut slots = self.slots.borrow_mut();
+        let (slot, command) = match command.correlation_id() {
+            None => (&mut slots[index], command),
+            Some(id) => match slots
+                .iter_mut()
+                .find(|s| matches!(s.requested, Some((r, _)) if r == id))
+            {
+                Some(slot) => {
+                    let device_id = slot.requested.and_then(|(_, device_id)| device_id);
+                    (slot, command.with_correlation_id(device_id))
+                }
+                None => {
+                    debug!("Dropping command with unknown correlation id {}", id);
+                    return Ok(());
+                }
+            },
+        };
+        to_slice(&command, &mut slot.frame).map_err(GatewayError::Codec)?;
+        slot.received = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// The update service taken by a device, returned to the gateway when dropped.
+struct Lease<'g, T, const N: usize>
+where
+    T: UpdateService,
+{
+    gateway: &'g Gateway<T, N>,
+    service: Option<T>,
+}
+
+impl<'g, T, const N: usize> Drop for Lease<'g, T, N>
+where
+    T: UpdateService,
+{
+    fn drop(&mut self) {
+        *self.gateway.service.borrow_mut() = self.service.take();
+        // Wake the devices waiting for the update service
+        for slot in self.gateway.slots.borrow_mut().iter_mut() {
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The update service of a device connected to a `Gateway`.
+pub struct GatewayService<'g, T, const N: usize>
+where
+    T: UpdateService,
+{
+    gateway: &'g Gateway<T, N>,
+    index: usize,
+    buf: [u8; FRAME_SIZE],
+}
+
+/// The error returned by the update service of a device connected to a `Gateway`.
+#[derive(Debug)]
+pub enum GatewayError<S> {
+    /// An error from the update service the devices are multiplexed over.
+    Service(S),
+    /// An error encoding/decoding the status or command.
+    Codec(postcard::Error),
+}
+
+impl<'g, T, const N: usize> UpdateService for GatewayService<'g, T, N>
+where
+    T: UpdateService,
+{
+    type Error = GatewayError<T::Error>;
+
+    async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+        let correlation_id = self.gateway.next_correlation_id();
+        {
+            let slot = &mut self.gateway.slots.borrow_mut()[self.index];
+            slot.requested.replace((correlation_id, status.correlation_id));
+            slot.received = false;
+        }
+        let upstream = Status {
+            correlation_id: Some(correlation_id),
+            ..status.clone()
+        };
+
+        loop {
+            {
+                let slot = &mut self.gateway.slots.borrow_mut()[self.index];
+                if slot.received {
+                    slot.received = false;
+                    self.buf.copy_from_slice(&slot.frame);
+                    break;
+                }
+            }
+
+            // Another device is waiting for a response from the update service
+            let Some(mut lease) = self.gateway.lease() else {
+                self.gateway.wait(self.index).await;
+                continue;
+            };
+
+            let service = lease.service.as_mut().unwrap();
+            let command = service.request(&upstream).await.map_err(GatewayError::Service)?;
+            let ours = command.correlation_id().map_or(true, |id| id == correlation_id);
+            self.gateway.route(self.index, command)?;
+            drop(lease);
+            if !ours {
+                // Let the device the command was for pick it up, before sending the status again
+                YieldNow(false).await;
+            }
+        }
+
+        from_bytes(&self.buf).map_err(GatewayError::Codec)
+    }
+}
+
+/// A future yielding to the executor once, so that the device a command was routed to can make progress.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            device::{testing::TokioDelay, Simulator},
+            service::InMemory,
+            DeviceStatus, FirmwareUpdater, UpdaterConfig,
+        },
+        core::convert::Infallible,
+        futures::{future::join, pin_mut},
+    };
+
+    /// An update service responding to every request with the response to the previous request, as a service
+    /// receiving the requests of several devices and responding out of order would.
+    struct Interleaved<'a> {
+        inner: InMemory<'a>,
+        held: [u8; FRAME_SIZE],
+        next: [u8; FRAME_SIZE],
+        rerouted: usize,
+    }
+
+    impl<'a> Interleaved<'a> {
+        fn new(inner: InMemory<'a>) -> Self {
+            let mut held = [0; FRAME_SIZE];
+            // The first response is for a request that was never sent
+            to_slice(&Command::new_wait(None, Some(u32::MAX)), &mut held).unwrap();
+            Self {
+                inner,
+                held,
+                next: [0; FRAME_SIZE],
+                rerouted: 0,
+            }
+        }
+    }
+
+    impl<'a> UpdateService for Interleaved<'a> {
+        type Error = Infallible;
+
+        async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            tokio::task::yield_now().await;
+            let command = self.inner.request(status).await?;
+            to_slice(&command, &mut self.next).unwrap();
+            core::mem::swap(&mut self.held, &mut self.next);
+
+            let command: Command = from_bytes(&self.next).unwrap();
+            if command.correlation_id() != status.correlation_id {
+                self.rerouted += 1;
+            }
+            Ok(command)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        let firmware = [1; 64];
+        let gateway: Gateway<_, 2> = Gateway::new(Interleaved::new(InMemory::new(b"2", &firmware)));
+
+        let mut first = Simulator::new(b"1");
+        let mut second = Simulator::new(b"1");
+        let mut first_updater = FirmwareUpdater::new(gateway.service(0), UpdaterConfig::default());
+        let mut second_updater = FirmwareUpdater::new(gateway.service(1), UpdaterConfig::default());
+        let (mut first_delay, mut second_delay) = (TokioDelay, TokioDelay);
+        let (first_status, second_status) = join(
+            first_updater.run(&mut first, &mut first_delay),
+            second_updater.run(&mut second, &mut second_delay),
+        )
+        .await;
+
+        assert_eq!(first_status.unwrap(), DeviceStatus::Updated);
+        assert_eq!(second_status.unwrap(), DeviceStatus::Updated);
+        assert_eq!(first.version(), b"2");
+        assert_eq!(second.version(), b"2");
+
+        assert!(gateway.into_inner().rerouted > 0);
+    }
+
+    /// An update service taking some time to respond.
+    struct Slow<'a>(InMemory<'a>);
+
+    impl<'a> UpdateService for Slow<'a> {
+        type Error = Infallible;
+
+        async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+            self.0.request(status).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gateway_waiting() {
+        let firmware = [1; 64];
+        let gateway: Gateway<_, 2> = Gateway::new(Slow(InMemory::new(b"2", &firmware)));
+
+        let mut first = Simulator::new(b"1");
+        let mut second = Simulator::new(b"1");
+        let mut first_updater = FirmwareUpdater::new(gateway.service(0), UpdaterConfig::default());
+        let mut second_updater = FirmwareUpdater::new(gateway.service(1), UpdaterConfig::default());
+        let (mut first_delay, mut second_delay) = (TokioDelay, TokioDelay);
+        let first_run = first_updater.run(&mut first, &mut first_delay);
+        let second_run = second_updater.run(&mut second, &mut second_delay);
+        pin_mut!(first_run, second_run);
+
+        // The device waiting for the update service is only polled when woken
+        let mut polls = 0;
+        let (first_status, second_status) = join(
+            first_run,
+            poll_fn(|cx| {
+                polls += 1;
+                second_run.as_mut().poll(cx)
+            }),
+        )
+        .await;
+
+        assert_eq!(first_status.unwrap(), DeviceStatus::Updated);
+        assert_eq!(second_status.unwrap(), DeviceStatus::Updated);
+        assert!(polls < 50, "polled {} times", polls);
+    }
+}
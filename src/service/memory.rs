@@ -1,14 +1,35 @@
 use {
-    crate::protocol::{Command, Status},
+    crate::protocol::{Capabilities, Codec, Command, Status},
     core::convert::Infallible,
 };
 
-use crate::traits::UpdateService;
+use crate::{blocking, traits::UpdateService};
 
 /// An in-memory updater service, useful in tests.
 pub struct InMemory<'a> {
     expected_version: &'a [u8],
     expected_firmware: &'a [u8],
+    #[cfg(feature = "sha256")]
+    expected_checksum: [u8; crate::CHECKSUM_SIZE],
+    signature: Option<&'a [u8]>,
+    abort: Option<(u32, Option<u32>)>,
+    patch: Option<(&'a [u8], &'a [u8])>,
+    compressed: Option<(Codec, &'a [u8])>,
+}
+
+/// The data sent to a device for writing the firmware.
+enum Payload<'a> {
+    Firmware(&'a [u8]),
+    Patch(&'a [u8]),
+    Compressed(Codec, &'a [u8]),
+}
+
+impl<'a> Payload<'a> {
+    fn data(&self) -> &'a [u8] {
+        match self {
+            Self::Firmware(data) | Self::Patch(data) | Self::Compressed(_, data) => data,
+        }
+    }
 }
 
 impl<'a> InMemory<'a> {
@@ -17,6 +38,92 @@ impl<'a> InMemory<'a> {
         Self {
             expected_version,
             expected_firmware,
+            #[cfg(feature = "sha256")]
+            expected_checksum: crate::checksum(expected_firmware),
+            signature: None,
+            abort: None,
+            patch: None,
+            compressed: None,
+        }
+    }
+
+    /// Create a new inmemory update service with a version and firmware, signed with the given signature.
+    pub fn new_signed(expected_version: &'a [u8], expected_firmware: &'a [u8], signature: &'a [u8]) -> Self {
+        Self {
+            signature: Some(signature),
+            ..Self::new(expected_version, expected_firmware)
+        }
+    }
+
+    /// Abort the update with the given reason once the device has written `offset` bytes of the firmware.
+    pub fn with_abort(self, offset: u32, reason: Option<u32>) -> Self {
+        Self {
+            abort: Some((offset, reason)),
+            ..self
+        }
+    }
+
+    /// Send the given patch instead of the firmware to devices running the `base` version and supporting patches.
+    pub fn with_patch(self, base: &'a [u8], patch: &'a [u8]) -> Self {
+        Self {
+            patch: Some((base, patch)),
+            ..self
+        }
+    }
+
+    /// Send the firmware compressed with the given codec to devices accepting the codec. The size in the start
+    /// command and the offsets are then in the compressed firmware, while the checksum sent in the swap command is
+    /// still the checksum of the firmware.
+    pub fn with_compressed(self, codec: Codec, compressed: &'a [u8]) -> Self {
+        Self {
+            compressed: Some((codec, compressed)),
+            ..self
+        }
+    }
+}
+
+impl<'a> InMemory<'a> {
+    fn start<'m>(&'m self, status: &Status<'m>) -> Command<'m> {
+        if status.supports(Capabilities::START) {
+            // Compressed firmware is sized like the offsets, in the compressed firmware
+            let size = match self.payload(status) {
+                Payload::Compressed(_, compressed) => compressed.len(),
+                _ => self.expected_firmware.len(),
+            };
+            Command::new_start(self.expected_version, size as u32, status.correlation_id)
+        } else {
+            self.block(status, 0, 128)
+        }
+    }
+
+    /// Return the data to send to the device: a patch if the device supports patches against its version, or else
+    /// the compressed firmware if the device accepts the codec, or else the full firmware.
+    fn payload(&self, status: &Status<'_>) -> Payload<'a> {
+        if let Some((_, patch)) = self
+            .patch
+            .filter(|(base, _)| *base == status.version.as_ref() && status.supports(Capabilities::DELTA))
+        {
+            Payload::Patch(patch)
+        } else if let Some((codec, compressed)) = self.compressed.filter(|(c, _)| status.supports(c.capability())) {
+            Payload::Compressed(codec, compressed)
+        } else {
+            Payload::Firmware(self.expected_firmware)
+        }
+    }
+
+    /// Return the command writing the next block of the payload at `offset`.
+    fn block<'m>(&'m self, status: &Status<'m>, offset: u32, mtu: u32) -> Command<'m> {
+        let payload = self.payload(status);
+        let data = payload.data();
+        let mtu = status.mtu.unwrap_or(mtu) as usize;
+        let to_copy = core::cmp::min(mtu, data.len() - offset as usize);
+        let s = &data[offset as usize..offset as usize + to_copy];
+        match payload {
+            Payload::Firmware(_) => Command::new_write(self.expected_version, offset, s, status.correlation_id),
+            Payload::Patch(_) => Command::new_patch(self.expected_version, offset, s, status.correlation_id),
+            Payload::Compressed(codec, _) => {
+                Command::new_write_compressed(self.expected_version, codec, offset, s, status.correlation_id)
+            }
         }
     }
 }
@@ -25,41 +132,58 @@ impl<'a> UpdateService for InMemory<'a> {
     type Error = Infallible;
 
     async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+        blocking::UpdateService::request(self, status)
+    }
+}
+
+impl<'a> blocking::UpdateService for InMemory<'a> {
+    type Error = Infallible;
+
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
         if self.expected_version == status.version.as_ref() {
             Ok(Command::new_sync(self.expected_version, None, status.correlation_id))
         } else if let Some(update) = &status.update {
             if update.version == self.expected_version {
-                if update.offset as usize >= self.expected_firmware.len() {
+                if let Some((_, reason)) = self
+                    .abort
+                    .filter(|(offset, _)| update.offset >= *offset && status.supports(Capabilities::ABORT))
+                {
+                    // Update is withdrawn, instruct device to discard the firmware
+                    Ok(Command::new_abort(reason, status.correlation_id))
+                } else if status.staged {
+                    // Update is staged, wait for the device to swap
+                    Ok(Command::new_wait(None, status.correlation_id))
+                } else if update.offset as usize >= self.payload(status).data().len() {
                     // Update is finished, instruct device to swap
-                    Ok(Command::new_swap(self.expected_version, &[], status.correlation_id))
+                    #[cfg(feature = "sha256")]
+                    let checksum = &self.expected_checksum[..];
+                    #[cfg(not(feature = "sha256"))]
+                    let checksum = &[];
+                    if let Some(signature) = self.signature.filter(|_| status.supports(Capabilities::SIGNED_SWAP)) {
+                        Ok(Command::new_signed_swap(
+                            self.expected_version,
+                            checksum,
+                            signature,
+                            status.correlation_id,
+                        ))
+                    } else {
+                        Ok(Command::new_swap(
+                            self.expected_version,
+                            checksum,
+                            status.correlation_id,
+                        ))
+                    }
                 } else {
                     // Continue updating
-                    let data = self.expected_firmware;
-                    let mtu = status.mtu.unwrap_or(16) as usize;
-                    let to_copy = core::cmp::min(mtu, data.len() - update.offset as usize);
-                    let s = &data[update.offset as usize..update.offset as usize + to_copy];
-                    Ok(Command::new_write(
-                        self.expected_version,
-                        update.offset,
-                        s,
-                        status.correlation_id,
-                    ))
+                    Ok(self.block(status, update.offset, 16))
                 }
             } else {
                 //  Unexpected version in status update, we need to start at 0
-                let data = self.expected_firmware;
-                let mtu = status.mtu.unwrap_or(128) as usize;
-                let to_copy = core::cmp::min(mtu, data.len());
-                let s = &data[..to_copy];
-                Ok(Command::new_write(self.expected_version, 0, s, status.correlation_id))
+                Ok(self.start(status))
             }
         } else {
             // No update status, start a new update
-            let data = self.expected_firmware;
-            let mtu = status.mtu.unwrap_or(128) as usize;
-            let to_copy = core::cmp::min(mtu, data.len());
-            let s = &data[..to_copy];
-            Ok(Command::new_write(self.expected_version, 0, s, status.correlation_id))
+            Ok(self.start(status))
         }
     }
 }
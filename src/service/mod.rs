@@ -1,5 +1,6 @@
 //! Implementations of the `UpdateService` trait.
+mod gateway;
 mod memory;
 mod serial;
 
-pub use {memory::*, serial::*};
+pub use {gateway::*, memory::*, serial::*};
@@ -1,4 +1,5 @@
 use {
+    embedded_io::ReadExactError,
     embedded_io_async::{Read, Write},
     postcard::{from_bytes, to_slice},
 };
@@ -9,6 +10,10 @@ use crate::{
 };
 
 /// Defines a fixed frame protocol based on types
+///
+/// Every status and command is sent zero-padded to a full frame. The encodings of older protocol revisions are
+/// shorter than the current ones, and only decode as the current types thanks to this padding, so transports must
+/// deliver complete frames.
 pub const FRAME_SIZE: usize = 1024;
 
 /// An update service based on a fixed-frame serial protocol, using `postcard` as the serialization format.
@@ -41,6 +46,17 @@ pub enum SerialError<T, C> {
     Transport(T),
     /// An error encoding/decoding the status or command.
     Codec(C),
+    /// The transport ended before a full frame was received.
+    Truncated,
+}
+
+impl<T, C> From<ReadExactError<T>> for SerialError<T, C> {
+    fn from(error: ReadExactError<T>) -> Self {
+        match error {
+            ReadExactError::UnexpectedEof => SerialError::Truncated,
+            ReadExactError::Other(e) => SerialError::Transport(e),
+        }
+    }
 }
 
 impl<T> UpdateService for Serial<T>
@@ -50,15 +66,15 @@ where
     type Error = SerialError<T::Error, postcard::Error>;
 
     async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+        self.buf.fill(0);
         to_slice(&status, &mut self.buf).map_err(SerialError::Codec)?;
-        let _ = self.transport.write(&self.buf).await.map_err(SerialError::Transport)?;
-
-        let _ = self
-            .transport
-            .read(&mut self.buf)
+        self.transport
+            .write_all(&self.buf)
             .await
             .map_err(SerialError::Transport)?;
 
+        self.transport.read_exact(&mut self.buf).await?;
+
         let c: Command = from_bytes(&self.buf).map_err(SerialError::Codec)?;
         Ok(c)
     }
@@ -0,0 +1,51 @@
+/// Verifies the signature of a firmware image before the updater allows it to be swapped.
+///
+/// The signed message is the checksum carried in `Command::SignedSwap`, which the updater
+/// has already compared against the digest of the written firmware.
+pub trait SignatureVerifier {
+    /// Return true if `signature` is a valid signature of `message` by `public_key`.
+    fn verify(&mut self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// A verifier that rejects every signature.
+///
+/// Used when no verifier has been configured, so that configuring a public key without a
+/// verifier fails closed.
+pub struct NoVerifier;
+
+impl SignatureVerifier for NoVerifier {
+    fn verify(&mut self, _public_key: &[u8], _message: &[u8], _signature: &[u8]) -> bool {
+        false
+    }
+}
+
+impl<V> SignatureVerifier for &mut V
+where
+    V: SignatureVerifier,
+{
+    fn verify(&mut self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        (**self).verify(public_key, message, signature)
+    }
+}
+
+/// A verifier for Ed25519 signatures.
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Verifier;
+
+#[cfg(feature = "ed25519")]
+impl SignatureVerifier for Ed25519Verifier {
+    fn verify(&mut self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        use ed25519_dalek::{Signature, VerifyingKey};
+
+        let Ok(public_key) = public_key.try_into() else {
+            return false;
+        };
+        let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+        key.verify_strict(message, &signature).is_ok()
+    }
+}
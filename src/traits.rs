@@ -1,5 +1,5 @@
 use {
-    crate::protocol::{Command, Status},
+    crate::protocol::{BootState, Capabilities, Codec, Command, FailureReport, Status},
     core::fmt::Debug,
 };
 
@@ -7,6 +7,7 @@ use {
 ///
 /// The service is responsible for establishing the connection to the firmware update
 /// service and performing the request-response cycle with the update service.
+#[allow(async_fn_in_trait)]
 pub trait UpdateService {
     /// Error type
     type Error: core::fmt::Debug;
@@ -75,10 +76,18 @@ where
 }
 
 /// Represents a device that can be updated by a `FirmwareUpdater`.
+#[allow(async_fn_in_trait)]
 pub trait FirmwareDevice {
     /// The preferred block size to be passed in write.
     const MTU: usize;
 
+    /// The maximum size of firmware that can be written to the device.
+    const CAPACITY: usize = usize::MAX;
+
+    /// The optional protocol features implemented by the device itself, such as the codecs accepted by
+    /// `write_compressed`. Advertised to the update service in addition to the features of the updater.
+    const CAPABILITIES: Capabilities = Capabilities::NONE;
+
     /// The expected version type for this device.
     type Version: FirmwareVersion;
 
@@ -88,15 +97,57 @@ pub trait FirmwareDevice {
     /// Return the status of the currently running firmware.
     async fn status(&mut self) -> Result<FirmwareStatus<Self::Version>, Self::Error>;
 
-    /// Prepare for starting the firmware update process.
-    async fn start(&mut self, version: &[u8]) -> Result<(), Self::Error>;
+    /// Prepare for starting the firmware update process. The total size of the firmware is provided
+    /// if known, and never exceeds `CAPACITY`. For compressed firmware, this is the size of the compressed firmware.
+    async fn start(&mut self, version: &[u8], size: Option<u32>) -> Result<(), Self::Error>;
 
     /// Write a block of firmware at the expected offset.
     async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
 
+    /// Write a block of compressed firmware at the expected offset in the compressed firmware. Only called with
+    /// codecs contained in `CAPABILITIES`.
+    ///
+    /// The checksum passed to `update` after writing compressed firmware is the checksum of the decompressed
+    /// firmware, which is not verified by the updater. The device verifies it, and checks the size of the
+    /// decompressed firmware against its capacity.
+    async fn write_compressed(&mut self, codec: Codec, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let _ = codec;
+        self.write(offset, data).await
+    }
+
     /// Finish the firmware write and mark device to be updated
     async fn update(&mut self, version: &[u8], checksum: &[u8]) -> Result<(), Self::Error>;
 
     /// Mark firmware as being in sync with the expected
     async fn synced(&mut self) -> Result<(), Self::Error>;
+
+    /// Store the progress of the firmware written so far, called after the updater stored a checkpoint, so that
+    /// devices keeping their own progress, such as `Decrypt`, can resume from the same offset after a reset.
+    async fn checkpoint(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Discard the firmware being written, after the update service aborted the update.
+    async fn abort(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the boot state of the running firmware. Devices with a bootloader that does not revert
+    /// firmware are always confirmed.
+    async fn boot_state(&mut self) -> Result<BootState, Self::Error> {
+        Ok(BootState::Confirmed)
+    }
+
+    /// Mark the running firmware as good, so that the bootloader does not revert to the previous firmware.
+    async fn mark_booted(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the code reported to the update service when the update fails with the given error.
+    ///
+    /// Device specific codes should start at `FailureReport::DEVICE_SPECIFIC`.
+    fn error_code(error: &Self::Error) -> u32 {
+        let _ = error;
+        FailureReport::DEVICE_ERROR
+    }
 }
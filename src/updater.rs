@@ -1,13 +1,22 @@
 use {
     crate::{
-        protocol::{Command, Status},
+        clock::{Clock, NoClock},
+        delta::{FirmwareReader, NoReader},
+        machine::{Action, DeviceProfile, Staged, UpdateMachine},
+        observer::{NoObserver, UpdateObserver},
+        policy::{AcceptAll, UpdatePolicy},
+        protocol::{Capabilities, Codec, Status},
+        resume::{NoStore, ResumeStore, RESUME_RECORD_SIZE},
+        retry::{Backoff, RetryPolicy},
+        signature::{NoVerifier, SignatureVerifier},
         traits::{FirmwareDevice, FirmwareVersion, UpdateService},
     },
-    embedded_hal_async::delay::DelayUs,
+    embedded_hal_async::delay::DelayNs,
     futures::{
         future::{select, Either},
         pin_mut,
     },
+    heapless::Vec,
 };
 
 /// The error types that the updater may return during the update process.
@@ -22,54 +31,234 @@ pub enum Error<D, S> {
     Device(D),
     /// Error from the update service.
     Service(S),
+    /// The checksum of the written firmware does not match the checksum sent by the update service.
+    ChecksumMismatch,
+    /// The firmware does not fit in the device. Contains the size of the firmware.
+    ImageTooLarge(u32),
+    /// The update service failed too many times in a row, or the deadline for the update passed.
+    RetriesExhausted(Failure<S>),
+    /// The firmware signature is missing or could not be verified with the configured public key.
+    InvalidSignature,
+    /// The version in a command from the update service does not match the version of the device.
+    VersionMismatch,
+    /// The update service kept sending blocks at an unexpected offset.
+    OffsetMismatch {
+        /// The offset the updater expected the next block to be written at.
+        expected: u32,
+        /// The offset of the last block sent by the update service.
+        offset: u32,
+    },
+    /// The patch sent by the update service could not be applied to the current firmware.
+    InvalidPatch,
+    /// The update service sent compressed blocks with a codec not accepted by the device.
+    UnsupportedCodec(Codec),
+    /// The version sent by the update service was rejected by the update policy of the device.
+    Rejected,
 }
 
+/// A failed request to the update service.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure<S> {
+    /// The update service returned an error.
+    Service(S),
+    /// The update service did not respond within the configured timeout.
+    Timeout,
+    /// The update service responded with a correlation id not matching the request.
+    CorrelationId,
+}
+
+/// The maximum size of the version and checksum of a staged firmware, kept by the updater until the application
+/// swaps to it.
+pub const STAGED_SIZE: usize = 64;
+
 /// The device status as determined after running the updater.
 #[derive(PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DeviceStatus {
-    /// The device is fully with the update service. The preferred delay before running again may be provided.
+    /// The device is fully with the update service. Contains the delay in seconds before running again, as
+    /// requested by the update service or else the initial delay of the retry policy.
     Synced(Option<u32>),
     /// The device firmware have been updated and the application should reset the device to start the next version of the application.
     Updated,
-}
-
-#[derive(Clone)]
-struct UpdaterState<F>
-where
-    F: FirmwareVersion,
-{
-    current_version: F,
-    next_offset: u32,
-    next_version: Option<F>,
+    /// The update service aborted the update, and the firmware being written has been discarded. The reason for
+    /// aborting may be provided.
+    Aborted(Option<u32>),
+    /// The firmware has been written and verified, but not swapped to, since `deferred_swap` is configured. The
+    /// application should call `FirmwareUpdater::swap` once resetting the device is not disruptive. Contains the
+    /// version of the staged firmware.
+    ReadyToSwap(Vec<u8, STAGED_SIZE>),
 }
 
 /// Configuration for the updater task.
+#[derive(Clone)]
 pub struct UpdaterConfig {
     /// Timeout used for update requests in milliseconds.
     pub timeout_ms: u32,
-    /// Backoff time when updates fail or time out.
-    pub backoff_ms: u32,
+    /// Policy for delaying requests when updates fail or time out.
+    pub retry: RetryPolicy,
+    /// Public key used to verify the firmware signature. If set, the updater refuses to swap
+    /// firmware that is not signed by this key.
+    pub public_key: Option<&'static [u8]>,
+    /// Number of consecutive blocks at an unexpected offset that are tolerated before giving up.
+    /// Duplicate blocks are dropped and gaps are re-requested from the expected offset.
+    pub max_offset_mismatches: u32,
+    /// Number of consecutive failed requests that are tolerated before giving up.
+    pub max_consecutive_failures: Option<u32>,
+    /// Deadline in milliseconds for the update, after which the update fails with `Error::RetriesExhausted`,
+    /// whether the requests fail or the update service keeps the device waiting.
+    ///
+    /// The elapsed time is measured with the clock configured with `FirmwareUpdater::with_clock`, or estimated from
+    /// the time the updater spends in delays and timed out requests.
+    pub deadline_ms: Option<u32>,
+    /// Number of blocks written between every checkpoint of the update progress in the resume store. Zero
+    /// disables checkpoints.
+    pub checkpoint_interval: u32,
+    /// Stop after verifying the written firmware, with `DeviceStatus::ReadyToSwap` instead of swapping to it, so
+    /// that the application swaps with `FirmwareUpdater::swap` when it is safe.
+    pub deferred_swap: bool,
 }
 
 impl Default for UpdaterConfig {
     fn default() -> Self {
         Self {
             timeout_ms: 15_000,
-            backoff_ms: 1_000,
+            retry: RetryPolicy::default(),
+            public_key: None,
+            max_offset_mismatches: 3,
+            max_consecutive_failures: None,
+            deadline_ms: None,
+            checkpoint_interval: 16,
+            deferred_swap: false,
+        }
+    }
+}
+
+/// The configuration and components shared by the async and blocking updaters, and the state they keep between
+/// runs. The builders of the updaters and of `UpdateMachine` are documented here.
+pub(crate) struct UpdaterParts<V, O, C, A> {
+    pub(crate) config: UpdaterConfig,
+    pub(crate) verifier: V,
+    pub(crate) observer: O,
+    pub(crate) clock: C,
+    pub(crate) policy: A,
+    pub(crate) correlation_id: u32,
+    pub(crate) backoff: Backoff,
+    pub(crate) staged: Option<Staged>,
+}
+
+impl UpdaterParts<NoVerifier, NoObserver, NoClock, AcceptAll> {
+    pub(crate) fn new(config: UpdaterConfig) -> Self {
+        Self {
+            config,
+            verifier: NoVerifier,
+            observer: NoObserver,
+            clock: NoClock,
+            policy: AcceptAll,
+            correlation_id: 0,
+            backoff: Backoff::new(0),
+            staged: None,
+        }
+    }
+}
+
+impl<V, O, C, A> UpdaterParts<V, O, C, A> {
+    /// Use the provided verifier for checking firmware signatures against the configured public key.
+    pub(crate) fn with_verifier<V2>(self, verifier: V2) -> UpdaterParts<V2, O, C, A> {
+        UpdaterParts {
+            config: self.config,
+            verifier,
+            observer: self.observer,
+            clock: self.clock,
+            policy: self.policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            staged: self.staged,
+        }
+    }
+
+    /// Use the provided observer for reporting the progress of the update.
+    pub(crate) fn with_observer<O2>(self, observer: O2) -> UpdaterParts<V, O2, C, A> {
+        UpdaterParts {
+            config: self.config,
+            verifier: self.verifier,
+            observer,
+            clock: self.clock,
+            policy: self.policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            staged: self.staged,
+        }
+    }
+
+    /// Use the provided clock for measuring the time elapsed since the start of the update, so that the deadline
+    /// of the update accounts for the time spent in requests and in writing firmware.
+    pub(crate) fn with_clock<C2>(self, clock: C2) -> UpdaterParts<V, O, C2, A> {
+        UpdaterParts {
+            config: self.config,
+            verifier: self.verifier,
+            observer: self.observer,
+            clock,
+            policy: self.policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            staged: self.staged,
+        }
+    }
+
+    /// Use the provided policy for accepting or rejecting the versions sent by the update service, such as
+    /// `Semver` for rejecting downgrades. Rejected versions are reported to the update service.
+    pub(crate) fn with_policy<A2>(self, policy: A2) -> UpdaterParts<V, O, C, A2> {
+        UpdaterParts {
+            config: self.config,
+            verifier: self.verifier,
+            observer: self.observer,
+            clock: self.clock,
+            policy,
+            correlation_id: self.correlation_id,
+            backoff: self.backoff,
+            staged: self.staged,
+        }
+    }
+
+    /// Seed the jitter of the retry policy with a value unique to the device, such as its serial number, so that
+    /// devices failing at the same time do not retry at the same time.
+    pub(crate) fn with_seed(self, seed: u32) -> Self {
+        Self {
+            backoff: Backoff::new(seed),
+            ..self
+        }
+    }
+
+    /// Seed the correlation ids of the status updates from the provided random number generator, so that
+    /// responses to requests from a previous run of the updater are not mistaken for responses to this run. The
+    /// random number generator also seeds the jitter of the retry policy.
+    #[cfg(feature = "rand_core")]
+    pub(crate) fn with_rng<G: rand_core::RngCore>(self, rng: &mut G) -> Self {
+        Self {
+            correlation_id: rng.next_u32(),
+            backoff: Backoff::new(rng.next_u32()),
+            ..self
         }
     }
 }
 
 /// The updater process that uses the update service to perform a firmware update check
 /// for a device. If the device needs to be updated, the updater will follow the update protocol
-pub struct FirmwareUpdater<T>
+pub struct FirmwareUpdater<T, V = NoVerifier, O = NoObserver, R = NoStore, P = NoReader, C = NoClock, A = AcceptAll>
 where
     T: UpdateService,
+    V: SignatureVerifier,
+    O: UpdateObserver,
+    R: ResumeStore,
+    P: FirmwareReader,
+    C: Clock,
+    A: UpdatePolicy,
 {
     service: T,
-    timeout_ms: u32,
-    backoff_ms: u32,
+    store: R,
+    reader: P,
+    parts: UpdaterParts<V, O, C, A>,
 }
 
 impl<T> FirmwareUpdater<T>
@@ -80,157 +269,395 @@ where
     pub fn new(service: T, config: UpdaterConfig) -> Self {
         Self {
             service,
-            timeout_ms: config.timeout_ms,
-            backoff_ms: config.backoff_ms,
+            store: NoStore,
+            reader: NoReader,
+            parts: UpdaterParts::new(config),
+        }
+    }
+}
+
+impl<T, V, O, R, P, C, A> FirmwareUpdater<T, V, O, R, P, C, A>
+where
+    T: UpdateService,
+    V: SignatureVerifier,
+    O: UpdateObserver,
+    R: ResumeStore,
+    P: FirmwareReader,
+    C: Clock,
+    A: UpdatePolicy,
+{
+    /// Use the provided verifier for checking firmware signatures against the configured public key.
+    pub fn with_verifier<V2: SignatureVerifier>(self, verifier: V2) -> FirmwareUpdater<T, V2, O, R, P, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            store: self.store,
+            reader: self.reader,
+            parts: self.parts.with_verifier(verifier),
+        }
+    }
+
+    /// Use the provided observer for reporting the progress of the update.
+    pub fn with_observer<O2: UpdateObserver>(self, observer: O2) -> FirmwareUpdater<T, V, O2, R, P, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            store: self.store,
+            reader: self.reader,
+            parts: self.parts.with_observer(observer),
+        }
+    }
+
+    /// Use the provided store for checkpointing the progress of the update, so that an update interrupted
+    /// by a reset is resumed from the last checkpoint.
+    pub fn with_store<R2: ResumeStore>(self, store: R2) -> FirmwareUpdater<T, V, O, R2, P, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            store,
+            reader: self.reader,
+            parts: self.parts,
         }
     }
 
-    async fn check<F: FirmwareDevice, D: DelayUs>(
+    /// Use the provided reader for reading back the current firmware, so that the update service can send a
+    /// patch against the current firmware instead of the full firmware.
+    pub fn with_reader<P2: FirmwareReader>(self, reader: P2) -> FirmwareUpdater<T, V, O, R, P2, C, A> {
+        FirmwareUpdater {
+            service: self.service,
+            store: self.store,
+            reader,
+            parts: self.parts,
+        }
+    }
+
+    /// Use the provided clock for measuring the time elapsed since the start of the update.
+    pub fn with_clock<C2: Clock>(self, clock: C2) -> FirmwareUpdater<T, V, O, R, P, C2, A> {
+        FirmwareUpdater {
+            service: self.service,
+            store: self.store,
+            reader: self.reader,
+            parts: self.parts.with_clock(clock),
+        }
+    }
+
+    /// Use the provided policy, such as `Semver`, for accepting or rejecting the versions sent by the update
+    /// service.
+    pub fn with_policy<A2: UpdatePolicy>(self, policy: A2) -> FirmwareUpdater<T, V, O, R, P, C, A2> {
+        FirmwareUpdater {
+            service: self.service,
+            store: self.store,
+            reader: self.reader,
+            parts: self.parts.with_policy(policy),
+        }
+    }
+
+    /// Seed the jitter of the retry policy with a value unique to the device, such as its serial number.
+    pub fn with_seed(self, seed: u32) -> Self {
+        Self {
+            parts: self.parts.with_seed(seed),
+            ..self
+        }
+    }
+
+    /// Seed the correlation ids of the status updates and the jitter of the retry policy from the provided random
+    /// number generator.
+    #[cfg(feature = "rand_core")]
+    pub fn with_rng<G: rand_core::RngCore>(self, rng: &mut G) -> Self {
+        Self {
+            parts: self.parts.with_rng(rng),
+            ..self
+        }
+    }
+
+    /// Follow the commands of the update service until the device is in sync, updated or aborted, and report the
+    /// failure to the update service if the update fails.
+    async fn check<F: FirmwareDevice, D: DelayNs>(
         &mut self,
         device: &mut F,
         delay: &mut D,
-    ) -> Result<(bool, Option<u32>), Error<F::Error, T::Error>> {
-        let mut state = {
-            let initial = device.status().await.map_err(Error::Device)?;
-            UpdaterState {
-                current_version: initial.current_version,
-                next_offset: initial.next_offset,
-                next_version: initial.next_version,
-            }
-        };
+    ) -> Result<DeviceStatus, Error<F::Error, T::Error>> {
+        let status = device.status().await.map_err(Error::Device)?;
+        let boot = device.boot_state().await.map_err(Error::Device)?;
+        let mut profile = DeviceProfile::of::<F>();
+        // Patches are applied by the updater, reading back the current firmware
+        profile.capabilities = profile.capabilities.difference(Capabilities::DELTA);
+        if P::ENABLED {
+            profile.capabilities = profile.capabilities.union(Capabilities::DELTA);
+        }
+        let mut machine = UpdateMachine::new(self.parts.config.clone(), profile, status, boot)
+            .with_verifier(&mut self.parts.verifier)
+            .with_clock(&mut self.parts.clock)
+            .with_policy(&mut self.parts.policy);
+        machine.correlation_id = self.parts.correlation_id;
+        machine.backoff = core::mem::replace(&mut self.parts.backoff, Backoff::new(0));
 
-        #[allow(unused_mut)]
-        #[allow(unused_assignments)]
-        #[allow(renamed_and_removed_lints)]
-        #[allow(mutable_borrow_reservation_conflict)]
-        loop {
-            let status = if let Some(next) = &state.next_version {
-                Status::update(
-                    state.current_version.as_ref(),
-                    Some(F::MTU as u32),
-                    state.next_offset,
-                    next.as_ref(),
-                    None,
-                )
-            } else {
-                Status::first(state.current_version.as_ref(), Some(F::MTU as u32), None)
-            };
-
-            debug!("Sending status: {:?}", status);
-
-            let mut next_state = state.clone();
-            let mut poll_opt = Some(self.backoff_ms / 1000);
-            {
-                let delay_fut = delay.delay_ms(self.timeout_ms);
-                let cmd_fut = self.service.request(&status);
-                pin_mut!(delay_fut);
-                pin_mut!(cmd_fut);
-                #[allow(clippy::single_match)]
-                match select(delay_fut, cmd_fut).await {
-                    Either::Right((cmd, _)) => match cmd {
-                        Ok(Command::Write {
-                            version,
-                            offset,
-                            data,
-                            correlation_id: _,
-                        }) => {
-                            if offset == 0 {
-                                debug!(
-                                    "Updating device firmware from {:?} to {:?}",
-                                    state.current_version,
-                                    version.as_ref()
-                                );
-                                device.start(version.as_ref()).await.map_err(Error::Device)?;
-                            }
-                            device.write(offset, data.as_ref()).await.map_err(Error::Device)?;
-
-                            next_state.next_offset += data.len() as u32;
-                            next_state
-                                .next_version
-                                .replace(F::Version::from_slice(version.as_ref()).map_err(|_| Error::DecodeVersion)?);
-                        }
-                        Ok(Command::Sync {
-                            version: _,
-                            poll,
-                            correlation_id: _,
-                        }) => {
-                            debug!("Device firmware is up to date");
-                            device.synced().await.map_err(Error::Device)?;
-                            if let Some(poll) = poll {
-                                if poll > 0 {
-                                    poll_opt.replace(poll);
-                                }
-                            }
-                            return Ok((true, poll_opt));
-                        }
-                        Ok(Command::Wait {
-                            poll,
-                            correlation_id: _,
-                        }) => {
-                            debug!("Instruction to wait for {:?} seconds", poll);
-                            if let Some(poll) = poll {
-                                if poll > 0 {
-                                    poll_opt.replace(poll);
-                                }
-                            }
-                        }
-                        Ok(Command::Swap {
-                            version,
-                            checksum,
-                            correlation_id: _,
-                        }) => {
-                            debug!("Swaping firmware");
-                            device
-                                .update(version.as_ref(), checksum.as_ref())
-                                .await
-                                .map_err(Error::Device)?;
-                            return Ok((false, None));
-                        }
-                        Err(e) => {
-                            #[cfg(feature = "defmt")]
-                            debug!("Error reporting status: {:?}", defmt::Debug2Format(&e));
-                            #[cfg(not(feature = "defmt"))]
-                            debug!("Error reporting status: {:?}", e);
-                        }
-                    },
-                    _ => {}
-                }
-            }
-            state = next_state;
-            if let Some(poll) = poll_opt {
-                delay.delay_ms(poll * 1000).await;
+        resume(&mut self.store, &mut machine).await;
+        if let Some(staged) = self.parts.staged.take() {
+            machine.restage(&staged.version, &staged.checksum);
+        }
+        let result = process(
+            &mut self.service,
+            device,
+            delay,
+            &mut machine,
+            &mut self.parts.observer,
+            &mut self.store,
+            &mut self.reader,
+        )
+        .await;
+        if let Err(e) = &result {
+            let timeout_ms = machine.config.timeout_ms;
+            if let Some(status) = machine.report(e, F::error_code) {
+                report(&mut self.service, delay, timeout_ms, &status).await;
             }
         }
+        self.parts.correlation_id = machine.correlation_id;
+        self.parts.staged = machine.take_staged();
+        self.parts.backoff = machine.backoff;
+        result
     }
 
-    /// Run the firmware update protocol. The update is finished with two outcomes:
+    /// Run the firmware update protocol. The update is finished with four outcomes:
     ///
     /// 1) The device is in sync, in which case `DeviceStatus::Synced` is returned.
     /// 2) The device is updated, in which case `DeviceStatus::Updated` is returned. It is the responsibility
-    ///    of called to reset the device in order to run the new firmware.
-    pub async fn run<F: FirmwareDevice, D: DelayUs>(
+    ///    of called to reset the device in order to run the new firmware. The new firmware is marked as booted
+    ///    once the update service confirms that the device is in sync.
+    /// 3) The update service aborted the update, in which case `DeviceStatus::Aborted` is returned.
+    /// 4) The firmware is verified and staged with `deferred_swap` configured, in which case
+    ///    `DeviceStatus::ReadyToSwap` is returned. Running the updater again reports the staged firmware to the
+    ///    update service, until the application swaps to it with `swap`.
+    ///
+    /// The returned future is `Send` when the update service, device, delay and other components of the updater
+    /// are, so that the update can be spawned on a multi-threaded executor.
+    ///
+    /// The updater is driven by an `UpdateMachine`, which can be used directly for driving the update with another
+    /// transport or timing.
+    pub async fn run<F: FirmwareDevice, D: DelayNs>(
         &mut self,
         device: &mut F,
         delay: &mut D,
     ) -> Result<DeviceStatus, Error<F::Error, T::Error>> {
-        let (synced, wait) = self.check(device, delay).await?;
-        if synced {
-            Ok(DeviceStatus::Synced(wait))
-        } else {
-            Ok(DeviceStatus::Updated)
+        self.check(device, delay).await.map_err(|e| {
+            self.parts.observer.failed();
+            e
+        })
+    }
+
+    /// Swap to the firmware staged by the last run of the updater with `deferred_swap` configured, once resetting
+    /// the device is not disruptive. Returns false if no firmware is staged, or the device is no longer writing
+    /// the staged firmware. It is the responsibility of the caller to reset the device after swapping.
+    pub async fn swap<F: FirmwareDevice>(&mut self, device: &mut F) -> Result<bool, F::Error> {
+        let Some(staged) = &self.parts.staged else {
+            return Ok(false);
+        };
+        let status = device.status().await?;
+        if status.next_version.as_ref().map(|v| v.as_ref()) != Some(&staged.version[..]) {
+            warn!("Staged firmware {:?} is no longer being written", &staged.version[..]);
+            self.parts.staged = None;
+            return Ok(false);
+        }
+        self.parts.observer.swapping(&staged.version);
+        device.update(&staged.version, &staged.checksum).await?;
+        self.parts.staged = None;
+        forget(&mut self.store).await;
+        Ok(true)
+    }
+}
+
+/// Send the status updates of the state machine to the update service, and perform the actions on the device, until
+/// the update is done.
+async fn process<T, F, D, V, C, A, O, R, P>(
+    service: &mut T,
+    device: &mut F,
+    delay: &mut D,
+    machine: &mut UpdateMachine<F::Version, V, C, A>,
+    observer: &mut O,
+    store: &mut R,
+    reader: &mut P,
+) -> Result<DeviceStatus, Error<F::Error, T::Error>>
+where
+    T: UpdateService,
+    F: FirmwareDevice,
+    D: DelayNs,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+    O: UpdateObserver,
+    R: ResumeStore,
+    P: FirmwareReader,
+{
+    loop {
+        let request = machine.request();
+        let status = request.status();
+        debug!("Sending status: {:?}", status);
+
+        let response = {
+            let delay_fut = delay.delay_ms(machine.config.timeout_ms);
+            let cmd_fut = service.request(&status);
+            pin_mut!(delay_fut);
+            pin_mut!(cmd_fut);
+            match select(delay_fut, cmd_fut).await {
+                Either::Right((Ok(command), _)) => Ok(command),
+                Either::Right((Err(e), _)) => {
+                    debug!("Error reporting status: {:?}", debug2format!(e));
+                    Err(Failure::Service(e))
+                }
+                Either::Left(_) => {
+                    debug!("Timeout reporting status");
+                    Err(Failure::Timeout)
+                }
+            }
+        };
+
+        for action in machine.handle::<F::Error, T::Error>(response)? {
+            if let Action::Done(status) = action {
+                return Ok(status);
+            }
+            if let Err(e) = perform(device, delay, machine, observer, store, reader, &action).await {
+                machine.failed(&action);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Perform an action of the state machine on the device.
+async fn perform<F, D, V, C, A, O, R, P, S>(
+    device: &mut F,
+    delay: &mut D,
+    machine: &mut UpdateMachine<F::Version, V, C, A>,
+    observer: &mut O,
+    store: &mut R,
+    reader: &mut P,
+    action: &Action<'_>,
+) -> Result<(), Error<F::Error, S>>
+where
+    F: FirmwareDevice,
+    D: DelayNs,
+    V: SignatureVerifier,
+    C: Clock,
+    A: UpdatePolicy,
+    O: UpdateObserver,
+    R: ResumeStore,
+    P: FirmwareReader,
+{
+    match *action {
+        Action::Start { version, size } => {
+            device.start(version, size).await.map_err(Error::Device)?;
+            observer.started(version);
+            forget(store).await;
+        }
+        Action::Write { offset, data, codec } => {
+            match codec {
+                Some(codec) => device.write_compressed(codec, offset, data).await,
+                None => device.write(offset, data).await,
+            }
+            .map_err(Error::Device)?;
+            observer.written(offset, data.len() as u32, machine.total());
+        }
+        Action::Patch { data } => {
+            let (offset, len) = machine.apply_patch(device, reader, data).await?;
+            observer.written(offset, len, machine.total());
         }
+        Action::Checkpoint => {
+            checkpoint(store, machine).await;
+            device.checkpoint().await.map_err(Error::Device)?;
+        }
+        Action::MarkBooted => device.mark_booted().await.map_err(Error::Device)?,
+        Action::Synced => {
+            device.synced().await.map_err(Error::Device)?;
+            observer.synced();
+        }
+        Action::Swap { version, checksum } => {
+            observer.swapping(version);
+            device.update(version, checksum).await.map_err(Error::Device)?;
+            forget(store).await;
+        }
+        Action::Stage { version, .. } => observer.staged(version),
+        Action::Abort { reason } => {
+            device.abort().await.map_err(Error::Device)?;
+            forget(store).await;
+            observer.aborted(reason);
+        }
+        Action::Wait(delay_ms) => {
+            observer.waiting(delay_ms);
+            delay.delay_ms(delay_ms).await;
+        }
+        Action::Retry { failures, delay_ms } => {
+            observer.retrying(failures, delay_ms);
+            delay.delay_ms(delay_ms).await;
+        }
+        Action::Done(_) => {}
+    }
+    Ok(())
+}
+
+/// Send a failure report to the update service. The response is ignored, since the updater is giving up.
+async fn report<T: UpdateService, D: DelayNs>(service: &mut T, delay: &mut D, timeout_ms: u32, status: &Status<'_>) {
+    debug!("Reporting failure: {:?}", status);
+    let delay_fut = delay.delay_ms(timeout_ms);
+    let cmd_fut = service.request(status);
+    pin_mut!(delay_fut);
+    pin_mut!(cmd_fut);
+    if let Either::Left(_) = select(delay_fut, cmd_fut).await {
+        debug!("Timeout reporting failure");
+    }
+}
+
+/// Restore the progress of the firmware being written from the last checkpoint in the store.
+async fn resume<R: ResumeStore, F: FirmwareVersion, V: SignatureVerifier, C: Clock, A: UpdatePolicy>(
+    store: &mut R,
+    machine: &mut UpdateMachine<F, V, C, A>,
+) {
+    let mut record = [0; RESUME_RECORD_SIZE];
+    match store.load(&mut record).await {
+        Ok(Some(len)) => machine.resume(&record[..len]),
+        Ok(None) => {}
+        Err(e) => {
+            warn!("Error loading checkpoint: {:?}", debug2format!(e));
+        }
+    }
+}
+
+/// Store the progress of the firmware being written as a checkpoint.
+async fn checkpoint<R: ResumeStore, F: FirmwareVersion, V: SignatureVerifier, C: Clock, A: UpdatePolicy>(
+    store: &mut R,
+    machine: &UpdateMachine<F, V, C, A>,
+) {
+    let mut record = [0; RESUME_RECORD_SIZE];
+    let Some(record) = machine.checkpoint(&mut record) else {
+        return;
+    };
+    if let Err(e) = store.store(record).await {
+        warn!("Error storing checkpoint: {:?}", debug2format!(e));
+    }
+}
+
+/// Remove the checkpoint from the store, after starting, finishing or aborting an update.
+async fn forget<R: ResumeStore>(store: &mut R) {
+    if let Err(e) = store.clear().await {
+        warn!("Error clearing checkpoint: {:?}", debug2format!(e));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{device::Simulator, service::InMemory, DeviceStatus, FirmwareUpdater, UpdaterConfig};
+    extern crate std;
+    use {
+        crate::{
+            device::{testing::Probe, Simulator},
+            service::InMemory,
+            BootState, Clock, Command, DeviceStatus, Error, Failure, FailureReport, FailureStage, FirmwareUpdater,
+            MemoryStore, ResumeStore, RetryPolicy, Status, UpdateObserver, UpdateService, UpdaterConfig,
+            RESUME_RECORD_SIZE,
+        },
+        std::vec::Vec,
+    };
 
     pub struct TokioDelay;
 
-    impl embedded_hal_async::delay::DelayUs for TokioDelay {
-        async fn delay_us(&mut self, i: u32) {
-            tokio::time::sleep(tokio::time::Duration::from_micros(i as u64)).await;
+    impl embedded_hal_async::delay::DelayNs for TokioDelay {
+        async fn delay_ns(&mut self, i: u32) {
+            tokio::time::sleep(tokio::time::Duration::from_nanos(i as u64)).await;
         }
 
         async fn delay_ms(&mut self, i: u32) {
@@ -238,6 +665,62 @@ mod tests {
         }
     }
 
+    /// An update service responding with the commands returned by a closure.
+    pub struct Scripted<F>(pub F);
+
+    impl<F> UpdateService for Scripted<F>
+    where
+        F: FnMut(&Status<'_>) -> Command<'static>,
+    {
+        type Error = core::convert::Infallible;
+
+        async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            Ok((self.0)(status))
+        }
+    }
+
+    /// An update service failing the given number of requests before responding.
+    pub struct Failing(usize);
+
+    impl UpdateService for Failing {
+        type Error = ();
+
+        async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+            if self.0 == 0 {
+                return Ok(Command::new_sync(b"1", None, status.correlation_id));
+            }
+            self.0 -= 1;
+            Err(())
+        }
+    }
+
+    /// A delay recording the delays in milliseconds.
+    struct RecordingDelay(Vec<u32>);
+
+    impl embedded_hal_async::delay::DelayNs for RecordingDelay {
+        async fn delay_ns(&mut self, i: u32) {
+            tokio::time::sleep(tokio::time::Duration::from_nanos(i as u64)).await;
+        }
+
+        async fn delay_ms(&mut self, i: u32) {
+            self.0.push(i);
+            tokio::time::sleep(tokio::time::Duration::from_millis(i as u64)).await;
+        }
+    }
+
+    fn config() -> UpdaterConfig {
+        UpdaterConfig {
+            timeout_ms: 1_000,
+            retry: RetryPolicy {
+                initial_delay_ms: 10,
+                multiplier: 2,
+                max_delay_ms: 40,
+                jitter_ms: 0,
+            },
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_update_protocol_synced() {
         let service = InMemory::new(b"1", &[1; 1024]);
@@ -247,13 +730,27 @@ mod tests {
             service,
             UpdaterConfig {
                 timeout_ms: 1_000,
-                backoff_ms: 10000,
+                retry: RetryPolicy {
+                    initial_delay_ms: 10_000,
+                    ..Default::default()
+                },
+                ..Default::default()
             },
         );
         let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
         assert_eq!(status, DeviceStatus::Synced(Some(10)));
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_protocol_send() {
+        let update = tokio::spawn(async move {
+            let mut device = Simulator::new(b"1");
+            let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &[1; 1024]), config());
+            updater.run(&mut device, &mut TokioDelay).await
+        });
+        assert_eq!(update.await.unwrap().unwrap(), DeviceStatus::Updated);
+    }
+
     #[tokio::test]
     async fn test_update_protocol_updated() {
         let service = InMemory::new(b"2", &[1; 1024]);
@@ -263,10 +760,782 @@ mod tests {
             service,
             UpdaterConfig {
                 timeout_ms: 1_000,
-                backoff_ms: 0,
+                ..Default::default()
+            },
+        );
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_sync_version_mismatch() {
+        let service = Scripted(|_: &Status<'_>| Command::new_sync(b"2", None, None));
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::VersionMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_swap_version_mismatch() {
+        let service = Scripted(|status: &Status<'_>| match &status.update {
+            None => Command::new_write(b"2", 0, &[1; 4], None),
+            Some(_) => Command::new_swap(b"3", &[], None),
+        });
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::VersionMismatch)));
+        assert_eq!(device.version(), b"1");
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_write_version_restart() {
+        let mut seen = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            let update = status.update.as_ref().map(|u| (u.version.to_vec(), u.offset));
+            seen.push(update.clone());
+            match update {
+                None => Command::new_write(b"2", 0, &[1; 4], None),
+                Some((v, 4)) if v == b"2" => Command::new_write(b"3", 4, &[1; 4], None),
+                Some(_) => Command::new_sync(b"1", None, None),
+            }
+        });
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+        assert_eq!(seen, [None, Some((b"2".to_vec(), 4)), Some((b"3".to_vec(), 0))]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_correlation_id() {
+        let mut seen = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            let id = status.correlation_id.unwrap();
+            seen.push(id);
+            if seen.len() == 1 {
+                // A stale response to an earlier request
+                Command::new_sync(b"2", None, Some(id.wrapping_sub(1)))
+            } else {
+                Command::new_sync(b"1", None, Some(id))
+            }
+        });
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+        assert_eq!(seen.len(), 2);
+        assert_ne!(seen[0], seen[1]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_backoff() {
+        let mut device = Simulator::new(b"1");
+        let mut delay = RecordingDelay(Vec::new());
+
+        let mut updater = FirmwareUpdater::new(Failing(4), config());
+        let status = updater.run(&mut device, &mut delay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+
+        let backoff: Vec<u32> = delay.0.into_iter().filter(|d| *d != 1_000).collect();
+        assert_eq!(backoff, [10, 20, 40, 40]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_jitter() {
+        let config = || UpdaterConfig {
+            retry: RetryPolicy {
+                jitter_ms: 5,
+                ..config().retry
+            },
+            ..config()
+        };
+
+        let mut delays = Vec::new();
+        for seed in [1, 2] {
+            let mut delay = RecordingDelay(Vec::new());
+            let mut updater = FirmwareUpdater::new(Failing(4), config()).with_seed(seed);
+            updater.run(&mut Simulator::new(b"1"), &mut delay).await.unwrap();
+            let backoff: Vec<u32> = delay.0.into_iter().filter(|d| *d != 1_000).collect();
+            for (d, base) in backoff.iter().zip([10, 20, 40, 40]) {
+                assert!((base..=base + 5).contains(d));
+            }
+            delays.push(backoff);
+        }
+        assert_ne!(delays[0], delays[1]);
+    }
+
+    #[cfg(feature = "rand_core")]
+    #[tokio::test]
+    async fn test_update_protocol_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut seen = Vec::new();
+        for seed in [1, 2] {
+            let service = Scripted(|status: &Status<'_>| {
+                seen.push(status.correlation_id);
+                Command::new_sync(b"1", None, status.correlation_id)
+            });
+            let mut updater = FirmwareUpdater::new(service, config()).with_rng(&mut StdRng::seed_from_u64(seed));
+            updater.run(&mut Simulator::new(b"1"), &mut TokioDelay).await.unwrap();
+        }
+        assert_eq!(seen.len(), 2);
+        assert_ne!(seen[0], seen[1]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_max_consecutive_failures() {
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(
+            Failing(usize::MAX),
+            UpdaterConfig {
+                max_consecutive_failures: Some(2),
+                ..config()
+            },
+        );
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::RetriesExhausted(Failure::Service(())))));
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_deadline() {
+        struct Unresponsive;
+
+        impl UpdateService for Unresponsive {
+            type Error = ();
+
+            async fn request<'m>(&'m mut self, _: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                core::future::pending().await
+            }
+        }
+
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(
+            Unresponsive,
+            UpdaterConfig {
+                timeout_ms: 10,
+                deadline_ms: Some(50),
+                ..config()
+            },
+        );
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::RetriesExhausted(Failure::Timeout))));
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_deadline_waiting() {
+        // An update service keeping the device waiting forever
+        let service = Scripted(|status: &Status<'_>| Command::new_wait(None, status.correlation_id));
+        let mut device = Simulator::new(b"1");
+        let mut delay = RecordingDelay(Vec::new());
+
+        let mut updater = FirmwareUpdater::new(
+            service,
+            UpdaterConfig {
+                deadline_ms: Some(50),
+                ..config()
+            },
+        );
+        let status = updater.run(&mut device, &mut delay).await;
+        assert!(matches!(status, Err(Error::RetriesExhausted(Failure::Timeout))));
+        let waits: Vec<u32> = delay.0.into_iter().filter(|d| *d != 1_000).collect();
+        assert_eq!(waits, [10; 6]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_clock() {
+        /// A clock advancing by a second every time it is read, like a slow link would.
+        struct Slow(u64);
+
+        impl Clock for Slow {
+            fn now_ms(&mut self) -> u64 {
+                self.0 += 1_000;
+                self.0
+            }
+        }
+
+        let mut device = Simulator::new(b"1");
+
+        // The delays between the failed requests add up to less than the deadline, but the requests do not
+        let mut updater = FirmwareUpdater::new(
+            Failing(10),
+            UpdaterConfig {
+                deadline_ms: Some(5_000),
+                ..config()
+            },
+        )
+        .with_clock(Slow(0));
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::RetriesExhausted(Failure::Service(())))));
+
+        let mut updater = FirmwareUpdater::new(
+            Failing(10),
+            UpdaterConfig {
+                deadline_ms: Some(5_000),
+                ..config()
+            },
+        );
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert_eq!(status.unwrap(), DeviceStatus::Synced(Some(0)));
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_observer() {
+        #[derive(Debug, PartialEq)]
+        enum Event {
+            Started(Vec<u8>),
+            Written(u32, u32, Option<u32>),
+            Swapping(Vec<u8>),
+            Retrying(u32),
+        }
+
+        #[derive(Default)]
+        struct Recorder(Vec<Event>);
+
+        impl UpdateObserver for Recorder {
+            fn started(&mut self, version: &[u8]) {
+                self.0.push(Event::Started(version.to_vec()));
+            }
+
+            fn written(&mut self, offset: u32, len: u32, total: Option<u32>) {
+                self.0.push(Event::Written(offset, len, total));
+            }
+
+            fn swapping(&mut self, version: &[u8]) {
+                self.0.push(Event::Swapping(version.to_vec()));
+            }
+
+            fn retrying(&mut self, failures: u32, _: u32) {
+                self.0.push(Event::Retrying(failures));
+            }
+        }
+
+        let service = InMemory::new(b"2", &[1; 512]);
+        let mut device = Simulator::new(b"1");
+        let mut recorder = Recorder::default();
+
+        let mut updater = FirmwareUpdater::new(service, config()).with_observer(&mut recorder);
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(
+            recorder.0,
+            [
+                Event::Started(b"2".to_vec()),
+                Event::Written(0, 256, Some(512)),
+                Event::Written(256, 256, Some(512)),
+                Event::Swapping(b"2".to_vec()),
+            ]
+        );
+
+        let mut device = Simulator::new(b"1");
+        let mut recorder = Recorder::default();
+        let mut updater = FirmwareUpdater::new(Failing(1), config()).with_observer(&mut recorder);
+        updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(recorder.0, [Event::Retrying(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_image_too_large() {
+        let service = InMemory::new(b"2", &[1; 1024]);
+        let mut device = Probe::<512>::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::ImageTooLarge(1024))));
+        assert_eq!(device.writes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_abort() {
+        let service = InMemory::new(b"2", &[1; 1024]).with_abort(512, Some(7));
+        let mut device = Probe::<{ usize::MAX }>::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Aborted(Some(7)));
+        assert!(device.aborted);
+        assert_eq!(device.inner.version(), b"1");
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_boot_state() {
+        for (boot, marked) in [
+            (BootState::Trial, true),
+            (BootState::RolledBack, false),
+            (BootState::Confirmed, false),
+        ] {
+            let mut seen = Vec::new();
+            let service = Scripted(|status: &Status<'_>| {
+                seen.push(status.boot);
+                Command::new_sync(b"1", None, None)
+            });
+            let mut device = Probe::<{ usize::MAX }>::new(b"1");
+            device.boot = boot;
+
+            let mut updater = FirmwareUpdater::new(service, config());
+            let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+            assert_eq!(status, DeviceStatus::Synced(Some(0)));
+            assert_eq!(seen, [Some(boot)]);
+            assert_eq!(device.marked, marked);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_resume() {
+        /// An update service recording the offsets reported by the device.
+        struct Offsets<'a> {
+            inner: InMemory<'a>,
+            offsets: &'a mut Vec<Option<u32>>,
+        }
+
+        impl<'a> UpdateService for Offsets<'a> {
+            type Error = core::convert::Infallible;
+
+            async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                self.offsets.push(status.update.as_ref().map(|u| u.offset));
+                self.inner.request(status).await
+            }
+        }
+
+        let firmware = [1; 1024];
+        let mut store = MemoryStore::new();
+        let mut device = Probe::<{ usize::MAX }>::new(b"1");
+        device.hang_after = Some(2);
+        let config = || UpdaterConfig {
+            checkpoint_interval: 1,
+            ..config()
+        };
+
+        {
+            let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &firmware), config()).with_store(&mut store);
+            let mut delay = TokioDelay;
+            let run = updater.run(&mut device, &mut delay);
+            let result = tokio::time::timeout(tokio::time::Duration::from_millis(100), run).await;
+            assert!(result.is_err());
+        }
+        assert_eq!(device.inner.next_offset(), 512);
+        device.hang_after = None;
+
+        let mut offsets = Vec::new();
+        let service = Offsets {
+            inner: InMemory::new(b"2", &firmware),
+            offsets: &mut offsets,
+        };
+        let mut updater = FirmwareUpdater::new(service, config()).with_store(&mut store);
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.inner.version(), b"2");
+        assert_eq!(offsets, [Some(512), Some(768), Some(1024)]);
+        assert_eq!(store.load(&mut [0; RESUME_RECORD_SIZE]).await, Ok(None));
+        assert_eq!(device.starts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_restart() {
+        let firmware = [1; 1024];
+        let mut device = Probe::<{ usize::MAX }>::new(b"1");
+        device.hang_after = Some(2);
+
+        {
+            let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &firmware), config());
+            let mut delay = TokioDelay;
+            let run = updater.run(&mut device, &mut delay);
+            let result = tokio::time::timeout(tokio::time::Duration::from_millis(100), run).await;
+            assert!(result.is_err());
+        }
+        assert_eq!(device.starts, 1);
+        device.hang_after = None;
+
+        // Without a checkpoint, the firmware is written again from offset 0 after preparing the device again. Without
+        // a checksum to restore, the update resumes from the offset reported by the device instead.
+        let mut updater = FirmwareUpdater::new(InMemory::new(b"2", &firmware), config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.inner.version(), b"2");
+        assert_eq!(device.starts, if cfg!(feature = "sha256") { 2 } else { 1 });
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_patch() {
+        /// An update service counting the patch blocks sent to the device.
+        struct Patches<'a> {
+            inner: InMemory<'a>,
+            patches: &'a mut usize,
+        }
+
+        impl<'a> UpdateService for Patches<'a> {
+            type Error = core::convert::Infallible;
+
+            async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                let command = self.inner.request(status).await?;
+                if let Command::Patch { .. } = command {
+                    *self.patches += 1;
+                }
+                Ok(command)
+            }
+        }
+
+        let old: Vec<u8> = (0..1024).map(|i| (i * 7) as u8).collect();
+        let mut new = Vec::new();
+        let mut patch = Vec::new();
+        let mut entry = |old: &[u8], changed: &[u8], extra: &[u8], seek: i32| {
+            patch.extend((changed.len() as u32).to_le_bytes());
+            patch.extend((extra.len() as u32).to_le_bytes());
+            patch.extend(seek.to_le_bytes());
+            patch.extend(old.iter().zip(changed).map(|(o, n)| n.wrapping_sub(*o)));
+            patch.extend(extra);
+            new.extend(changed);
+            new.extend(extra);
+        };
+        let changed: Vec<u8> = old[..600]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if i % 50 == 0 { b.wrapping_add(1) } else { *b })
+            .collect();
+        entry(&old[..600], &changed, &[0xaa; 100], -400);
+        entry(&old[200..500], &old[200..500], &[], 0);
+
+        let mut patches = 0;
+        let service = Patches {
+            inner: InMemory::new(b"2", &new).with_patch(b"1", &patch),
+            patches: &mut patches,
+        };
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(service, config()).with_reader(&old[..]);
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.version(), b"2");
+        assert_eq!(patches, 4);
+
+        // Without a reader, the full firmware is sent
+        let mut patches = 0;
+        let service = Patches {
+            inner: InMemory::new(b"2", &new).with_patch(b"1", &patch),
+            patches: &mut patches,
+        };
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(patches, 0);
+
+        // A patch reading past the current firmware is rejected
+        let mut device = Simulator::new(b"1");
+        let service = InMemory::new(b"2", &new).with_patch(b"1", &patch);
+        let mut updater = FirmwareUpdater::new(service, config()).with_reader(&old[..500]);
+        let result = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(result, Err(Error::InvalidPatch)));
+    }
+
+    static FIRMWARE: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29,
+        30, 31,
+    ];
+
+    fn firmware_write(offset: u32) -> Command<'static> {
+        let offset_usize = offset as usize;
+        Command::new_write(b"2", offset, &FIRMWARE[offset_usize..offset_usize + 4], None)
+    }
+
+    fn firmware_checksum() -> &'static [u8] {
+        #[cfg(feature = "sha256")]
+        {
+            static CHECKSUM: std::sync::OnceLock<[u8; crate::CHECKSUM_SIZE]> = std::sync::OnceLock::new();
+            CHECKSUM.get_or_init(|| crate::checksum(&FIRMWARE))
+        }
+        #[cfg(not(feature = "sha256"))]
+        &[]
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_offset_recovery() {
+        let checksum = firmware_checksum();
+        let (mut duplicated, mut skipped) = (false, false);
+        let service = Scripted(move |status: &Status<'_>| match &status.update {
+            None => firmware_write(0),
+            Some(u) if u.offset == 8 && !duplicated => {
+                duplicated = true;
+                firmware_write(4)
+            }
+            Some(u) if u.offset == 16 && !skipped => {
+                skipped = true;
+                firmware_write(20)
+            }
+            Some(u) if u.offset as usize >= FIRMWARE.len() => Command::new_swap(b"2", checksum, None),
+            Some(u) => firmware_write(u.offset),
+        });
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.version(), b"2");
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_offset_mismatch() {
+        let service = Scripted(|status: &Status<'_>| match &status.update {
+            None => firmware_write(0),
+            Some(u) => firmware_write(u.offset + 4),
+        });
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::OffsetMismatch { expected: 4, offset: 8 })));
+        assert_eq!(device.version(), b"1");
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_failure_report() {
+        fn record(reports: &mut Vec<(FailureStage, u32, Vec<u8>)>, status: &Status<'_>) {
+            if let Some(f) = &status.failure {
+                reports.push((f.stage, f.code, f.version.to_vec()));
+            }
+        }
+
+        let mut reports = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            record(&mut reports, status);
+            match &status.update {
+                None => firmware_write(0),
+                Some(u) => firmware_write(u.offset),
+            }
+        });
+        let mut device = Probe::<{ usize::MAX }>::new(b"1");
+        device.fail_at = Some((8, 42));
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::Device(42))));
+        assert_eq!(
+            reports,
+            [(FailureStage::Write, FailureReport::DEVICE_SPECIFIC + 42, b"2".to_vec())]
+        );
+
+        let mut reports = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            record(&mut reports, status);
+            match &status.update {
+                None => firmware_write(0),
+                Some(_) => Command::new_swap(b"3", &[], None),
+            }
+        });
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut Simulator::new(b"1"), &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::VersionMismatch)));
+        assert_eq!(
+            reports,
+            [(FailureStage::Swap, FailureReport::VERSION_MISMATCH, b"3".to_vec())]
+        );
+
+        // Disagreeing about the current version is reported as a swap that did not take effect
+        let mut reports = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            record(&mut reports, status);
+            Command::new_sync(b"2", None, None)
+        });
+        let mut updater = FirmwareUpdater::new(service, config());
+        let status = updater.run(&mut Simulator::new(b"1"), &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::VersionMismatch)));
+        assert_eq!(
+            reports,
+            [(FailureStage::Swap, FailureReport::VERSION_MISMATCH, b"2".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_deferred_swap() {
+        /// An update service recording whether the statuses report a staged firmware.
+        struct Recording<'a> {
+            inner: InMemory<'a>,
+            staged: Vec<bool>,
+        }
+
+        impl<'a> UpdateService for Recording<'a> {
+            type Error = core::convert::Infallible;
+
+            async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                self.staged.push(status.staged);
+                self.inner.request(status).await
+            }
+        }
+
+        let firmware = [1; 1024];
+        let service = Recording {
+            inner: InMemory::new(b"2", &firmware),
+            staged: Vec::new(),
+        };
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(
+            service,
+            UpdaterConfig {
+                deferred_swap: true,
+                ..config()
             },
         );
+
+        let staged = DeviceStatus::ReadyToSwap(heapless::Vec::from_slice(b"2").unwrap());
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, staged);
+        assert_eq!(device.version(), b"1");
+        assert_eq!(updater.service.staged.last(), Some(&true));
+
+        // The staged firmware is reported again without being written again
+        updater.service.staged.clear();
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, staged);
+        assert_eq!(updater.service.staged, [true]);
+
+        assert!(updater.swap(&mut device).await.unwrap());
+        assert_eq!(device.version(), b"2");
+        assert!(!updater.swap(&mut device).await.unwrap());
+
+        let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+        assert_eq!(status, DeviceStatus::Synced(Some(0)));
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_policy() {
+        let mut reports = Vec::new();
+        let service = Scripted(|status: &Status<'_>| {
+            if let Some(f) = &status.failure {
+                reports.push((f.stage, f.code, f.version.to_vec()));
+            }
+            firmware_write(0)
+        });
+        let mut device = Simulator::new(b"3");
+        let mut updater = FirmwareUpdater::new(service, config()).with_policy(crate::Semver);
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::Rejected)));
+        assert_eq!(reports, [(FailureStage::Start, FailureReport::REJECTED, b"2".to_vec())]);
+        assert_eq!(device.version(), b"3");
+
+        let service = InMemory::new(b"2", &[1; 1024]);
+        let mut device = Simulator::new(b"1");
+        let mut updater = FirmwareUpdater::new(service, config()).with_policy(crate::Semver);
         let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
         assert_eq!(status, DeviceStatus::Updated);
+        assert_eq!(device.version(), b"2");
+    }
+
+    #[cfg(feature = "sha256")]
+    #[tokio::test]
+    async fn test_update_protocol_checksum_mismatch() {
+        use crate::Bytes;
+
+        struct BadChecksum<'a>(InMemory<'a>);
+
+        impl<'a> UpdateService for BadChecksum<'a> {
+            type Error = core::convert::Infallible;
+
+            async fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Result<Command<'m>, Self::Error> {
+                match self.0.request(status).await? {
+                    Command::Swap {
+                        version,
+                        correlation_id,
+                        checksum: _,
+                    } => Ok(Command::Swap {
+                        version,
+                        correlation_id,
+                        checksum: Bytes::default(),
+                    }),
+                    command => Ok(command),
+                }
+            }
+        }
+
+        let service = BadChecksum(InMemory::new(b"2", &[1; 1024]));
+        let mut device = Simulator::new(b"1");
+
+        let mut updater = FirmwareUpdater::new(
+            service,
+            UpdaterConfig {
+                timeout_ms: 1_000,
+                ..Default::default()
+            },
+        );
+        let status = updater.run(&mut device, &mut TokioDelay).await;
+        assert!(matches!(status, Err(Error::ChecksumMismatch)));
+        assert_eq!(device.version(), b"1");
+    }
+
+    #[cfg(feature = "ed25519")]
+    mod signed {
+        use {
+            super::TokioDelay,
+            crate::{
+                device::Simulator, service::InMemory, DeviceStatus, Ed25519Verifier, Error, FirmwareUpdater,
+                UpdaterConfig,
+            },
+            ed25519_dalek::{Signer, SigningKey},
+        };
+
+        // Test vector 1 from RFC 8032
+        const SECRET_KEY: [u8; 32] = [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4, 0x44, 0x49,
+            0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+        ];
+        const PUBLIC_KEY: [u8; 32] = [
+            0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a, 0x0e, 0xe1,
+            0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+        ];
+
+        const FIRMWARE: [u8; 1024] = [1; 1024];
+
+        fn config() -> UpdaterConfig {
+            UpdaterConfig {
+                timeout_ms: 1_000,
+                public_key: Some(&PUBLIC_KEY),
+                ..Default::default()
+            }
+        }
+
+        fn sign(firmware: &[u8]) -> [u8; 64] {
+            SigningKey::from_bytes(&SECRET_KEY)
+                .sign(&crate::checksum(firmware))
+                .to_bytes()
+        }
+
+        #[tokio::test]
+        async fn test_update_protocol_signed() {
+            let signature = sign(&FIRMWARE);
+            let service = InMemory::new_signed(b"2", &FIRMWARE, &signature);
+            let mut device = Simulator::new(b"1");
+
+            let mut updater = FirmwareUpdater::new(service, config()).with_verifier(Ed25519Verifier);
+            let status = updater.run(&mut device, &mut TokioDelay).await.unwrap();
+            assert_eq!(status, DeviceStatus::Updated);
+            assert_eq!(device.version(), b"2");
+        }
+
+        #[tokio::test]
+        async fn test_update_protocol_bad_signature() {
+            let signature = sign(&[2; 1024]);
+            let service = InMemory::new_signed(b"2", &FIRMWARE, &signature);
+            let mut device = Simulator::new(b"1");
+
+            let mut updater = FirmwareUpdater::new(service, config()).with_verifier(Ed25519Verifier);
+            let status = updater.run(&mut device, &mut TokioDelay).await;
+            assert!(matches!(status, Err(Error::InvalidSignature)));
+            assert_eq!(device.version(), b"1");
+        }
+
+        #[tokio::test]
+        async fn test_update_protocol_unsigned() {
+            let service = InMemory::new(b"2", &FIRMWARE);
+            let mut device = Simulator::new(b"1");
+
+            let mut updater = FirmwareUpdater::new(service, config()).with_verifier(Ed25519Verifier);
+            let status = updater.run(&mut device, &mut TokioDelay).await;
+            assert!(matches!(status, Err(Error::InvalidSignature)));
+            assert_eq!(device.version(), b"1");
+        }
     }
 }
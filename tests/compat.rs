@@ -0,0 +1,492 @@
+//! Wire compatibility of the protocol types.
+//!
+//! Every protocol revision has a set of frozen `postcard` encodings. Encodings of a revision must never
+//! change once released, since devices in the field decode them positionally. New fields must be
+//! appended to `Status`, and new commands appended to `Command`.
+//!
+//! `postcard` cannot tell a missing trailing field from the end of the input, so the shorter encodings of
+//! older revisions only decode as the current types when zero-padded to a full frame. The serial transports
+//! always send full frames, and reject frames cut short. Devices of revision 0 pad their status with the leftovers
+//! of an earlier frame instead, which `Status::from_frame` does not decode as the fields of later revisions.
+
+use embedded_update::{BootState, Capabilities, Codec, Command, FailureReport, FailureStage, Protocol, Status};
+
+/// Size of the fixed frames used by the serial device and update service.
+const FRAME_SIZE: usize = 1024;
+
+mod revision_0 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4];
+
+    pub const WAIT: &[u8] = &[0, 1, 7, 1, 10];
+    pub const SYNC: &[u8] = &[1, 1, 49, 1, 7, 1, 10];
+    pub const WRITE: &[u8] = &[2, 1, 50, 1, 7, 128, 4, 4, 1, 2, 3, 4];
+    pub const SWAP: &[u8] = &[3, 1, 50, 1, 7, 2, 170, 187];
+
+    // Devices of revision 0 encode their status over the last command received, leaving its trailing bytes in the
+    // frame. These are `STATUS_FIRST` and `STATUS_UPDATE` sent over `Write` commands with the given blocks.
+    /// Over a block of `[1, 2, 3, 4, 5, 6, 7, 8]`, decoding as revision 2 with an invalid failure.
+    pub const STATUS_FIRST_OVER_WRITE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+    /// Over a block of `[1, 1, 31, 0]`, decoding as revision 1 with capabilities it does not define.
+    pub const STATUS_FIRST_OVER_CAPABILITIES: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 1, 31, 0];
+    /// Over a block of `[1, 9, 127, 0, 0, 0]`, decoding as an unknown revision 9 with every capability.
+    pub const STATUS_UPDATE_OVER_WRITE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 9, 127, 0, 0, 0];
+}
+
+mod revision_1 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 1, 3];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 1, 3];
+
+    pub const SIGNED_SWAP: &[u8] = &[4, 1, 50, 1, 7, 2, 170, 187, 2, 204, 221];
+    pub const START: &[u8] = &[5, 1, 50, 1, 7, 128, 8];
+}
+
+mod revision_2 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 2, 3, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 2, 3, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 2, 3, 1, 3, 6, 1, 50];
+}
+
+mod revision_3 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 3, 7, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 3, 7, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 3, 7, 1, 3, 6, 1, 50];
+
+    pub const ABORT: &[u8] = &[6, 1, 7, 1, 3];
+}
+
+mod revision_4 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 4, 7, 0, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 4, 7, 0, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 4, 7, 1, 3, 6, 1, 50, 0];
+    pub const STATUS_TRIAL: &[u8] = &[1, 50, 1, 128, 2, 1, 7, 0, 1, 4, 7, 0, 1, 1];
+}
+
+mod revision_5 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 5, 15, 0, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 5, 15, 0, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 5, 15, 1, 3, 6, 1, 50, 0];
+    pub const STATUS_TRIAL: &[u8] = &[1, 50, 1, 128, 2, 1, 7, 0, 1, 5, 15, 0, 1, 1];
+    pub const STATUS_NO_DELTA: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 5, 7, 0, 0];
+
+    pub const PATCH: &[u8] = &[7, 1, 50, 1, 7, 128, 4, 4, 1, 2, 3, 4];
+}
+
+mod revision_6 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 6, 31, 0, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 6, 31, 0, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 6, 31, 1, 3, 6, 1, 50, 0];
+    pub const STATUS_TRIAL: &[u8] = &[1, 50, 1, 128, 2, 1, 7, 0, 1, 6, 31, 0, 1, 1];
+    pub const STATUS_BASIC: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 6, 7, 0, 0];
+
+    pub const WRITE_COMPRESSED: &[u8] = &[8, 1, 50, 1, 7, 128, 4, 0, 4, 1, 2, 3, 4];
+}
+
+mod revision_7 {
+    pub const STATUS_FIRST: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 7, 7, 0, 0, 0];
+    pub const STATUS_UPDATE: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 7, 7, 0, 0, 0];
+    pub const STATUS_FAILED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 7, 7, 1, 3, 6, 1, 50, 0, 0];
+    pub const STATUS_TRIAL: &[u8] = &[1, 50, 1, 128, 2, 1, 7, 0, 1, 7, 7, 0, 1, 1, 0];
+    pub const STATUS_ALL: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 0, 1, 7, 31, 0, 0, 0];
+    pub const STATUS_STAGED: &[u8] = &[1, 49, 1, 128, 2, 1, 7, 1, 1, 50, 128, 4, 1, 7, 7, 0, 0, 1];
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    let mut buf = [0; FRAME_SIZE];
+    postcard::to_slice(value, &mut buf).unwrap().to_vec()
+}
+
+/// Pad an encoded message to a full frame, as sent by the serial transports and required to decode the encodings
+/// of older revisions.
+fn frame(data: &[u8]) -> [u8; FRAME_SIZE] {
+    let mut buf = [0; FRAME_SIZE];
+    buf[..data.len()].copy_from_slice(data);
+    buf
+}
+
+#[test]
+fn revision_current() {
+    assert_eq!(Protocol::REVISION, 7);
+    assert_eq!(Protocol::current().capabilities, Capabilities::DEFAULT);
+    assert!(!Capabilities::DEFAULT.contains(Capabilities::DELTA));
+    assert!(!Capabilities::DEFAULT.contains(Capabilities::HEATSHRINK));
+}
+
+#[test]
+fn status_revision_7() {
+    assert_eq!(
+        encode(&Status::first(b"1", Some(256), Some(7))),
+        revision_7::STATUS_FIRST
+    );
+    assert_eq!(
+        encode(&Status::update(b"1", Some(256), 512, b"2", Some(7))),
+        revision_7::STATUS_UPDATE
+    );
+    let failure = FailureReport::new(FailureStage::Verify, FailureReport::CHECKSUM_MISMATCH, b"2");
+    assert_eq!(
+        encode(&Status::failed(b"1", Some(256), failure, Some(7))),
+        revision_7::STATUS_FAILED
+    );
+    assert_eq!(
+        encode(&Status::first(b"2", Some(256), Some(7)).with_boot(BootState::Trial)),
+        revision_7::STATUS_TRIAL
+    );
+    assert_eq!(
+        encode(&Status::first(b"1", Some(256), Some(7)).with_capabilities(Capabilities::ALL)),
+        revision_7::STATUS_ALL
+    );
+    assert_eq!(
+        encode(&Status::update(b"1", Some(256), 512, b"2", Some(7)).with_staged()),
+        revision_7::STATUS_STAGED
+    );
+
+    let status: Status = postcard::from_bytes(revision_7::STATUS_TRIAL).unwrap();
+    assert_eq!(status.protocol, Some(Protocol::current()));
+    assert_eq!(status.boot, Some(BootState::Trial));
+    assert!(!status.staged);
+
+    let status: Status = postcard::from_bytes(revision_7::STATUS_STAGED).unwrap();
+    assert_eq!(status.update.unwrap().offset, 512);
+    assert!(status.staged);
+}
+
+#[test]
+fn status_revision_6_from_device() {
+    for fixture in [
+        revision_6::STATUS_FIRST,
+        revision_6::STATUS_UPDATE,
+        revision_6::STATUS_FAILED,
+        revision_6::STATUS_TRIAL,
+    ] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(6));
+        assert!(status.supports(Capabilities::DELTA));
+        assert!(status.supports(Codec::Heatshrink.capability()));
+        assert!(!status.staged);
+    }
+
+    let data = frame(revision_6::STATUS_BASIC);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    assert!(!status.supports(Capabilities::HEATSHRINK));
+}
+
+#[test]
+fn status_revision_5_from_device() {
+    for fixture in [
+        revision_5::STATUS_FIRST,
+        revision_5::STATUS_UPDATE,
+        revision_5::STATUS_FAILED,
+        revision_5::STATUS_TRIAL,
+    ] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(5));
+        assert!(status.supports(Capabilities::DELTA));
+        assert!(!status.supports(Capabilities::HEATSHRINK));
+    }
+
+    let data = frame(revision_5::STATUS_NO_DELTA);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    assert!(!status.supports(Capabilities::DELTA));
+}
+
+#[test]
+fn status_revision_4_from_device() {
+    for fixture in [
+        revision_4::STATUS_FIRST,
+        revision_4::STATUS_UPDATE,
+        revision_4::STATUS_FAILED,
+        revision_4::STATUS_TRIAL,
+    ] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(4));
+        assert!(status.supports(Capabilities::ABORT));
+        assert!(!status.supports(Capabilities::DELTA));
+    }
+
+    let data = frame(revision_4::STATUS_TRIAL);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    assert_eq!(status.boot, Some(BootState::Trial));
+}
+
+#[test]
+fn status_revision_3_from_device() {
+    for fixture in [
+        revision_3::STATUS_FIRST,
+        revision_3::STATUS_UPDATE,
+        revision_3::STATUS_FAILED,
+    ] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(3));
+        assert!(status.supports(Capabilities::ABORT));
+        assert_eq!(status.boot, None);
+    }
+}
+
+#[test]
+fn status_revision_2_from_device() {
+    for fixture in [revision_2::STATUS_FIRST, revision_2::STATUS_UPDATE] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(2));
+        assert!(!status.supports(Capabilities::ABORT));
+        assert!(status.failure.is_none());
+    }
+
+    let data = frame(revision_2::STATUS_FAILED);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    let failure = status.failure.unwrap();
+    assert_eq!(failure.stage, FailureStage::Verify);
+    assert_eq!(failure.code, FailureReport::CHECKSUM_MISMATCH);
+    assert_eq!(failure.version, b"2");
+}
+
+#[test]
+fn status_revision_1_from_device() {
+    for fixture in [revision_1::STATUS_FIRST, revision_1::STATUS_UPDATE] {
+        let data = frame(fixture);
+        let status: Status = postcard::from_bytes(&data).unwrap();
+        assert_eq!(status.protocol.map(|p| p.revision), Some(1));
+        assert!(status.supports(Capabilities::SIGNED_SWAP));
+        assert!(status.supports(Capabilities::START));
+        assert!(!status.supports(Capabilities::ABORT));
+        assert!(status.failure.is_none());
+    }
+}
+
+#[test]
+fn status_revision_0_from_device() {
+    let data = frame(revision_0::STATUS_FIRST);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    assert_eq!(status.version, b"1");
+    assert_eq!(status.mtu, Some(256));
+    assert_eq!(status.correlation_id, Some(7));
+    assert!(status.update.is_none());
+    assert_eq!(status.protocol, None);
+    assert!(!status.supports(Capabilities::START));
+
+    let data = frame(revision_0::STATUS_UPDATE);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    let update = status.update.as_ref().unwrap();
+    assert_eq!(update.version, b"2");
+    assert_eq!(update.offset, 512);
+    assert_eq!(status.protocol, None);
+    assert!(!status.supports(Capabilities::SIGNED_SWAP));
+    assert!(status.failure.is_none());
+}
+
+#[test]
+fn status_unpadded_from_device() {
+    // The trailing fields added by later revisions are only decoded as absent from a padded frame
+    for fixture in [
+        revision_0::STATUS_FIRST,
+        revision_1::STATUS_FIRST,
+        revision_3::STATUS_FIRST,
+        revision_6::STATUS_FIRST,
+    ] {
+        assert!(postcard::from_bytes::<Status>(fixture).is_err());
+        assert!(postcard::from_bytes::<Status>(&frame(fixture)).is_ok());
+    }
+    // The current revision decodes without padding
+    assert!(postcard::from_bytes::<Status>(revision_7::STATUS_FIRST).is_ok());
+}
+
+#[test]
+fn status_revision_0_leftovers_from_device() {
+    for fixture in [
+        revision_0::STATUS_FIRST_OVER_WRITE,
+        revision_0::STATUS_FIRST_OVER_CAPABILITIES,
+        revision_0::STATUS_UPDATE_OVER_WRITE,
+    ] {
+        let data = frame(fixture);
+        // Decoding the leftovers as the fields of later revisions fails, or makes up capabilities
+        if let Ok(status) = postcard::from_bytes::<Status>(&data) {
+            assert!(status.supports(Capabilities::START));
+        }
+
+        let status = Status::from_frame(&data).unwrap();
+        assert_eq!(status.version, b"1");
+        assert_eq!(status.mtu, Some(256));
+        assert_eq!(status.correlation_id, Some(7));
+        assert_eq!(status.protocol, None);
+        assert!(status.failure.is_none());
+        assert_eq!(status.boot, None);
+        assert!(!status.staged);
+    }
+
+    let data = frame(revision_0::STATUS_UPDATE_OVER_WRITE);
+    let status = Status::from_frame(&data).unwrap();
+    assert_eq!(status.update.unwrap().offset, 512);
+}
+
+#[test]
+fn status_from_frame() {
+    // Statuses padded with zeros decode as with `postcard`
+    for fixture in [
+        revision_0::STATUS_FIRST,
+        revision_0::STATUS_UPDATE,
+        revision_1::STATUS_FIRST,
+        revision_2::STATUS_FAILED,
+        revision_3::STATUS_FAILED,
+        revision_4::STATUS_TRIAL,
+        revision_5::STATUS_UPDATE,
+        revision_6::STATUS_FAILED,
+        revision_7::STATUS_TRIAL,
+        revision_7::STATUS_STAGED,
+    ] {
+        let data = frame(fixture);
+        let expected: Status = postcard::from_bytes(&data).unwrap();
+        let status = Status::from_frame(&data).unwrap();
+        assert_eq!(status.version, expected.version);
+        assert_eq!(status.update.map(|u| u.offset), expected.update.map(|u| u.offset));
+        assert_eq!(status.protocol, expected.protocol);
+        assert_eq!(status.failure.map(|f| f.code), expected.failure.map(|f| f.code));
+        assert_eq!(status.boot, expected.boot);
+        assert_eq!(status.staged, expected.staged);
+    }
+
+    // The fields after the revision advertised by the device are not decoded
+    let mut data = frame(revision_1::STATUS_FIRST);
+    data[revision_1::STATUS_FIRST.len()..][..3].copy_from_slice(&[1, 3, 6]);
+    let status = Status::from_frame(&data).unwrap();
+    assert_eq!(status.protocol.map(|p| p.revision), Some(1));
+    assert!(status.failure.is_none());
+
+    assert!(Protocol::current().is_known());
+    assert!(!Protocol {
+        revision: Protocol::REVISION + 1,
+        capabilities: Capabilities::DEFAULT
+    }
+    .is_known());
+}
+
+#[test]
+fn status_revision_0_to_service() {
+    // A service of revision 0 decodes the fields it knows, and ignores the rest of the frame.
+    assert!(revision_7::STATUS_FIRST.starts_with(revision_0::STATUS_FIRST));
+    assert!(revision_7::STATUS_UPDATE.starts_with(revision_0::STATUS_UPDATE));
+}
+
+#[test]
+fn status_revision_0_cbor() {
+    use serde_cbor::Value;
+
+    let encoded = serde_cbor::to_vec(&Value::Map(
+        [
+            (Value::Text("version".into()), Value::Bytes(b"1".to_vec())),
+            (Value::Text("mtu".into()), Value::Integer(256)),
+            (Value::Text("correlation_id".into()), Value::Null),
+            (Value::Text("update".into()), Value::Null),
+        ]
+        .into_iter()
+        .collect(),
+    ))
+    .unwrap();
+    let status: Status = serde_cbor::from_slice(&encoded).unwrap();
+    assert_eq!(status.version, b"1");
+    assert_eq!(status.mtu, Some(256));
+    assert_eq!(status.protocol, None);
+}
+
+#[test]
+fn commands_revision_0() {
+    assert_eq!(encode(&Command::new_wait(Some(10), Some(7))), revision_0::WAIT);
+    assert_eq!(encode(&Command::new_sync(b"1", Some(10), Some(7))), revision_0::SYNC);
+    assert_eq!(
+        encode(&Command::new_write(b"2", 512, &[1, 2, 3, 4], Some(7))),
+        revision_0::WRITE
+    );
+    assert_eq!(
+        encode(&Command::new_swap(b"2", &[0xaa, 0xbb], Some(7))),
+        revision_0::SWAP
+    );
+
+    for fixture in [revision_0::WAIT, revision_0::SYNC, revision_0::WRITE, revision_0::SWAP] {
+        let command: Command = postcard::from_bytes(fixture).unwrap();
+        assert_eq!(command.correlation_id(), Some(7));
+    }
+}
+
+#[test]
+fn commands_revision_1() {
+    assert_eq!(
+        encode(&Command::new_signed_swap(b"2", &[0xaa, 0xbb], &[0xcc, 0xdd], Some(7))),
+        revision_1::SIGNED_SWAP
+    );
+    assert_eq!(encode(&Command::new_start(b"2", 1024, Some(7))), revision_1::START);
+
+    for fixture in [revision_1::SIGNED_SWAP, revision_1::START] {
+        let command: Command = postcard::from_bytes(fixture).unwrap();
+        assert_eq!(command.correlation_id(), Some(7));
+    }
+}
+
+#[test]
+fn commands_revision_3() {
+    assert_eq!(encode(&Command::new_abort(Some(3), Some(7))), revision_3::ABORT);
+
+    let command: Command = postcard::from_bytes(revision_3::ABORT).unwrap();
+    assert!(matches!(
+        command,
+        Command::Abort {
+            reason: Some(3),
+            correlation_id: Some(7)
+        }
+    ));
+}
+
+#[test]
+fn commands_revision_5() {
+    assert_eq!(
+        encode(&Command::new_patch(b"2", 512, &[1, 2, 3, 4], Some(7))),
+        revision_5::PATCH
+    );
+
+    let command: Command = postcard::from_bytes(revision_5::PATCH).unwrap();
+    assert!(matches!(command, Command::Patch { offset: 512, .. }));
+    assert_eq!(command.correlation_id(), Some(7));
+}
+
+#[test]
+fn commands_revision_6() {
+    assert_eq!(
+        encode(&Command::new_write_compressed(
+            b"2",
+            Codec::Heatshrink,
+            512,
+            &[1, 2, 3, 4],
+            Some(7)
+        )),
+        revision_6::WRITE_COMPRESSED
+    );
+
+    let command: Command = postcard::from_bytes(revision_6::WRITE_COMPRESSED).unwrap();
+    assert!(matches!(
+        command,
+        Command::WriteCompressed {
+            offset: 512,
+            codec: Codec::Heatshrink,
+            ..
+        }
+    ));
+    assert_eq!(command.correlation_id(), Some(7));
+}
+
+#[tokio::test]
+async fn in_memory_revision_0() {
+    use embedded_update::{service::InMemory, UpdateService};
+
+    let mut service = InMemory::new(b"2", &[1; 1024]);
+
+    let data = frame(revision_0::STATUS_FIRST);
+    let status: Status = postcard::from_bytes(&data).unwrap();
+    let command = service.request(&status).await.unwrap();
+    assert!(matches!(command, Command::Write { offset: 0, .. }));
+
+    let status = Status::first(b"1", Some(256), Some(7));
+    let command = service.request(&status).await.unwrap();
+    assert!(matches!(command, Command::Start { size: 1024, .. }));
+}